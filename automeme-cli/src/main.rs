@@ -4,9 +4,12 @@ extern crate clap;
 use clap::Parser;
 
 use arboard::{Clipboard, ImageData};
-use automeme_core::{get_template_from_disk, render_template};
+use automeme_core::{render_template, AutomemeError, TemplateStore};
 use image::RgbaImage;
 use std::borrow::Cow;
+use std::env;
+use std::path::PathBuf;
+use std::process;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -15,9 +18,29 @@ pub struct Cli {
     /// The template to use
     #[arg(short, long, default_value = "random")]
     template_name: String,
+
+    /// The directory to load templates from [default: templates, or the
+    /// built-in templates if this was built with the embed feature]
+    #[arg(long, env = "TEMPLATE_DIR")]
+    template_dir: Option<String>,
+
+    /// Fonts to try for characters the template fonts cannot draw [env:
+    /// TEMPLATE_FALLBACK_FONTS, separated like PATH]
+    #[arg(long = "fallback-font")]
+    fallback_fonts: Vec<PathBuf>,
+
+    /// Template pack archives to load templates from, used for any template
+    /// not in the template directory [env: TEMPLATE_PACKS, separated like
+    /// PATH]
+    #[arg(long = "pack")]
+    packs: Vec<PathBuf>,
+
+    /// Validate every template in the template directory and exit
+    #[arg(long)]
+    validate: bool,
 }
 
-fn save_image_to_clipboard(image: &RgbaImage) -> () {
+fn save_image_to_clipboard(image: &RgbaImage) {
     let mut buffer = Vec::new();
     for pixel in image.pixels() {
         buffer.extend_from_slice(&pixel.0);
@@ -32,13 +55,77 @@ fn save_image_to_clipboard(image: &RgbaImage) -> () {
         .unwrap();
 }
 
+fn validate_all_templates(store: &TemplateStore) {
+    let reports = store.validate_all_templates().unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        process::exit(1);
+    });
+    let mut num_invalid = 0;
+    for report in &reports {
+        for warning in &report.warnings {
+            println!("warning: {}: {warning}", report.template_name);
+        }
+        for error in &report.errors {
+            println!("error: {}: {error}", report.template_name);
+        }
+        if !report.is_valid() {
+            num_invalid += 1;
+        }
+    }
+    println!("{} templates checked, {num_invalid} invalid", reports.len());
+    if num_invalid > 0 {
+        process::exit(1);
+    }
+}
+
+/// Use the paths given for an option, or if there are none, the paths in an
+/// environment variable, separated the same way as in PATH.
+fn paths_or_env(paths: Vec<PathBuf>, key: &str) -> Vec<PathBuf> {
+    if !paths.is_empty() {
+        return paths;
+    }
+    env::var_os(key)
+        .map(|paths| env::split_paths(&paths).collect())
+        .unwrap_or_default()
+}
+
 fn main() {
     // parse args from command line
     let cli = Cli::parse();
-    if let Some(template) = get_template_from_disk(&cli.template_name).unwrap() {
-        let image = render_template(template);
-        save_image_to_clipboard(&image);
-    } else {
-        println!("Template not found.");
+    let store = match cli.template_dir {
+        Some(template_dir) => TemplateStore::new(template_dir),
+        #[cfg(feature = "embed")]
+        None => TemplateStore::embedded(),
+        #[cfg(not(feature = "embed"))]
+        None => TemplateStore::new("templates"),
+    }
+    .with_fallback_fonts(paths_or_env(cli.fallback_fonts, "TEMPLATE_FALLBACK_FONTS"));
+    let store = paths_or_env(cli.packs, "TEMPLATE_PACKS")
+        .into_iter()
+        .try_fold(store, TemplateStore::with_pack)
+        .unwrap_or_else(|e| {
+            eprintln!("Error: {e}");
+            process::exit(1);
+        });
+    if cli.validate {
+        validate_all_templates(&store);
+        return;
+    }
+    match store
+        .get_template_from_disk(&cli.template_name)
+        .and_then(render_template)
+    {
+        Ok(image) => save_image_to_clipboard(&image),
+        Err(AutomemeError::TemplateNotFound(name)) => {
+            eprintln!(
+                "Template '{name}' not found in {}. Use --template-dir to load templates from another directory.",
+                store.root().display()
+            );
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            process::exit(1);
+        }
     }
 }
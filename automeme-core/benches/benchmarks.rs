@@ -1,13 +1,15 @@
 //! Benchmarks for the main crate
 
-use automeme_core::{
-    get_template_from_disk, get_template_names, render_template, startup_check_all_resources,
-    startup_load_all_resources,
-};
+use automeme_core::{render_template, TemplateStore};
 use criterion::{criterion_group, criterion_main, Criterion};
 use std::time::Duration;
 
+fn bench_store() -> TemplateStore {
+    TemplateStore::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../templates"))
+}
+
 pub fn standard_benches(c: &mut Criterion) {
+    let store = bench_store();
     let mut group = c.benchmark_group("automeme-core");
     group
         .sample_size(100)
@@ -16,34 +18,32 @@ pub fn standard_benches(c: &mut Criterion) {
         .measurement_time(Duration::new(10, 0));
 
     group.bench_function("get names from template files", |b| {
-        b.iter(|| get_template_names().unwrap())
+        b.iter(|| store.get_template_names().unwrap())
     });
 
     group.bench_function("load one template and resources from disk", |b| {
-        b.iter(|| {
-            get_template_from_disk(&"weatherboy".to_owned())
-                .unwrap()
-                .unwrap()
-        })
+        b.iter(|| store.get_template_from_disk("weatherboy").unwrap())
     });
 
     group.bench_function("load all templates and validate all resources", |b| {
-        b.iter(|| startup_check_all_resources().unwrap())
+        b.iter(|| store.startup_check_all_resources().unwrap())
     });
 
-    let template = get_template_from_disk(&"weatherboy".to_owned())
-        .unwrap()
-        .unwrap();
+    let template = store.get_template_from_disk("weatherboy").unwrap();
     group.bench_function("render a loaded template", |b| {
-        b.iter(|| render_template(template.clone()))
+        b.iter(|| render_template(template.clone()).unwrap())
+    });
+
+    let mut template = store.get_template_from_disk("bliss").unwrap();
+    template.text_fields[0].text = "one does not simply render a border".to_owned();
+    group.bench_function("render a high resolution template with a border", |b| {
+        b.iter(|| render_template(template.clone()).unwrap())
     });
 
     group.bench_function("load and render a template by name", |b| {
         b.iter(|| {
-            let template = get_template_from_disk(&"weatherboy".to_owned())
-                .unwrap()
-                .unwrap();
-            render_template(template);
+            let template = store.get_template_from_disk("weatherboy").unwrap();
+            render_template(template).unwrap();
         })
     });
 
@@ -51,6 +51,7 @@ pub fn standard_benches(c: &mut Criterion) {
 }
 
 pub fn long_benches(c: &mut Criterion) {
+    let store = bench_store();
     let mut group = c.benchmark_group("automeme-core");
     group
         .sample_size(10)
@@ -59,7 +60,7 @@ pub fn long_benches(c: &mut Criterion) {
         .measurement_time(Duration::new(30, 0));
 
     group.bench_function("load all templates and all resources into memory", |b| {
-        b.iter(|| startup_load_all_resources())
+        b.iter(|| store.startup_load_all_resources())
     });
 
     group.finish();
@@ -0,0 +1,72 @@
+//! Boxes drawn behind text, such as caption plates and label fills.
+
+use image::{Rgba, RgbaImage};
+
+/// Draw a filled box with rounded corners on a transparent layer of the given
+/// size. The box spans `[left, top, right, bottom]` in pixels, and its edges
+/// and corners are antialiased. The radius is limited to half the shorter
+/// side, so a large radius gives a pill shape.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn background_layer(
+    size: (u32, u32),
+    [left, top, right, bottom]: [f32; 4],
+    radius: f32,
+    color: [u8; 3],
+    opacity: f32,
+) -> RgbaImage {
+    let half_size = ((right - left) / 2.0, (bottom - top) / 2.0);
+    let center = (left + half_size.0, top + half_size.1);
+    let radius = radius.clamp(0.0, half_size.0.min(half_size.1).max(0.0));
+    let [red, green, blue] = color;
+
+    // Signed distance from each pixel center to the edge of the box, negative
+    // inside it, found from the box with its corners cut back by the radius
+    RgbaImage::from_fn(size.0, size.1, |x, y| {
+        let dx = (x as f32 + 0.5 - center.0).abs() - (half_size.0 - radius);
+        let dy = (y as f32 + 0.5 - center.1).abs() - (half_size.1 - radius);
+        let outside = dx.max(0.0).hypot(dy.max(0.0));
+        let distance = outside + dx.max(dy).min(0.0) - radius;
+        let coverage = (0.5 - distance).clamp(0.0, 1.0) * opacity.clamp(0.0, 1.0);
+        Rgba([red, green, blue, (coverage * 255.0).round() as u8])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::background_layer;
+
+    #[test]
+    fn test_background_layer() {
+        let alpha = |layer: &image::RgbaImage, x: u32, y: u32| layer.get_pixel(x, y).0[3];
+
+        // A square box fills whole pixels and nothing around it
+        let layer = background_layer((20, 20), [5.0, 5.0, 15.0, 10.0], 0.0, [10, 20, 30], 1.0);
+        assert_eq!(layer.get_pixel(5, 5).0, [10, 20, 30, 255]);
+        assert_eq!(alpha(&layer, 14, 9), 255);
+        assert_eq!(alpha(&layer, 4, 5), 0);
+        assert_eq!(alpha(&layer, 15, 5), 0);
+        assert_eq!(alpha(&layer, 10, 10), 0);
+
+        // Edges through the middle of pixels cover half of them
+        let layer = background_layer((20, 20), [5.5, 5.0, 15.0, 10.0], 0.0, [0; 3], 1.0);
+        assert_eq!(alpha(&layer, 5, 7), 128);
+
+        // Rounded corners are cut off, but not the middle of each side, and
+        // opacity applies all over
+        let layer = background_layer((20, 20), [0.0, 0.0, 20.0, 20.0], 6.0, [0; 3], 0.5);
+        assert_eq!(alpha(&layer, 0, 0), 0);
+        assert_eq!(alpha(&layer, 19, 19), 0);
+        assert_eq!(alpha(&layer, 0, 10), 128);
+        assert_eq!(alpha(&layer, 10, 0), 128);
+        assert_eq!(alpha(&layer, 3, 3), 128);
+        assert_eq!(alpha(&layer, 10, 10), 128);
+
+        // The radius never goes past half of the shorter side
+        let pill = background_layer((40, 10), [0.0, 0.0, 40.0, 10.0], 100.0, [0; 3], 1.0);
+        assert_eq!(alpha(&pill, 0, 0), 0);
+        assert_eq!(alpha(&pill, 20, 0), 255);
+        assert_eq!(alpha(&pill, 1, 5), 255);
+    }
+}
@@ -0,0 +1,149 @@
+//! Softening layers, such as shadows, with an approximate Gaussian blur.
+
+use crate::transform::unpremultiply;
+use image::RgbaImage;
+
+/// Number of box blurs run one after another, which together come close to a
+/// Gaussian blur.
+const BOX_PASSES: usize = 3;
+
+/// Blur a layer so each pixel spreads over about `radius` pixels around it,
+/// with three box blurs approximating a Gaussian blur with a standard
+/// deviation of a third of the radius. The layer grows to hold everything
+/// that spreads past its edges, so the position of its top-left corner
+/// relative to the original layer's is also returned. Colors are weighted by
+/// their alpha so transparent pixels don't darken the edges.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_possible_wrap)]
+pub fn blur_layer(layer: &RgbaImage, radius: f32) -> (RgbaImage, (i32, i32)) {
+    let box_radii = box_radii(radius / 3.0);
+    let padding: usize = box_radii.iter().sum();
+    if padding == 0 {
+        return (layer.clone(), (0, 0));
+    }
+    let width = layer.width() as usize + 2 * padding;
+    let height = layer.height() as usize + 2 * padding;
+
+    // Copy the layer into the middle of the larger one, premultiplied
+    let mut pixels = vec![[0.0f32; 4]; width * height];
+    for (x, y, pixel) in layer.enumerate_pixels() {
+        let alpha = f32::from(pixel.0[3]);
+        pixels[(y as usize + padding) * width + x as usize + padding] = [
+            f32::from(pixel.0[0]) * alpha,
+            f32::from(pixel.0[1]) * alpha,
+            f32::from(pixel.0[2]) * alpha,
+            alpha,
+        ];
+    }
+
+    // Blur along every row and then every column, once for each box
+    let mut line = Vec::with_capacity(width.max(height));
+    for box_radius in box_radii {
+        for y in 0..height {
+            line.clear();
+            line.extend_from_slice(&pixels[y * width..(y + 1) * width]);
+            box_blur_line(&line, box_radius, |x, pixel| pixels[y * width + x] = pixel);
+        }
+        for x in 0..width {
+            line.clear();
+            line.extend((0..height).map(|y| pixels[y * width + x]));
+            box_blur_line(&line, box_radius, |y, pixel| pixels[y * width + x] = pixel);
+        }
+    }
+
+    let mut blurred = RgbaImage::new(width as u32, height as u32);
+    for (pixel, color) in blurred.pixels_mut().zip(pixels) {
+        *pixel = unpremultiply(color);
+    }
+    let padding = padding as i32;
+    (blurred, (-padding, -padding))
+}
+
+/// The radius of each box blur that together best match a Gaussian blur with
+/// the given standard deviation.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+fn box_radii(deviation: f32) -> [usize; BOX_PASSES] {
+    let passes = BOX_PASSES as f32;
+    let variance = 12.0 * deviation * deviation;
+
+    // Use boxes of two neighbouring odd widths, as many narrow ones as keep
+    // the total variance closest to the Gaussian's
+    let ideal_width = (variance / passes + 1.0).sqrt();
+    let mut narrow = ideal_width.floor().max(1.0);
+    if narrow % 2.0 == 0.0 {
+        narrow -= 1.0;
+    }
+    let narrow_passes =
+        ((variance - passes * narrow * narrow - 4.0 * passes * narrow - 3.0 * passes)
+            / (-4.0 * narrow - 4.0))
+            .round()
+            .clamp(0.0, passes) as usize;
+    let narrow_radius = (narrow as usize - 1) / 2;
+    let mut radii = [narrow_radius + 1; BOX_PASSES];
+    for radius in &mut radii[..narrow_passes] {
+        *radius = narrow_radius;
+    }
+    radii
+}
+
+/// Average each pixel in a line with the pixels up to `radius` away on either
+/// side, counting pixels past the ends as transparent, and pass each result
+/// on with its index.
+#[allow(clippy::cast_precision_loss)]
+fn box_blur_line(line: &[[f32; 4]], radius: usize, mut write: impl FnMut(usize, [f32; 4])) {
+    let scale = 1.0 / (2 * radius + 1) as f32;
+    let mut sum = [0.0f32; 4];
+    for pixel in line.iter().take(radius) {
+        add(&mut sum, pixel, 1.0);
+    }
+    for index in 0..line.len() {
+        if let Some(entering) = line.get(index + radius) {
+            add(&mut sum, entering, 1.0);
+        }
+        write(index, sum.map(|channel| channel * scale));
+        if let Some(leaving) = index.checked_sub(radius).and_then(|start| line.get(start)) {
+            add(&mut sum, leaving, -1.0);
+        }
+    }
+}
+
+/// Add a pixel to a running sum, times a weight.
+fn add(sum: &mut [f32; 4], pixel: &[f32; 4], weight: f32) {
+    for (total, channel) in sum.iter_mut().zip(pixel) {
+        *total += channel * weight;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::blur_layer;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_blur_layer() {
+        let mut layer = RgbaImage::new(9, 9);
+        layer.put_pixel(4, 4, Rgba([200, 100, 0, 255]));
+
+        // Nothing changes without a radius
+        assert_eq!(blur_layer(&layer, 0.0), (layer.clone(), (0, 0)));
+
+        // A dot spreads out evenly, fading away from the middle but keeping
+        // its color and about the same total alpha
+        let (blurred, offset) = blur_layer(&layer, 6.0);
+        assert!(offset.0 < 0 && offset.0 == offset.1);
+        let padding = offset.0.unsigned_abs();
+        assert_eq!(blurred.dimensions(), (9 + 2 * padding, 9 + 2 * padding));
+        let center = 4 + padding;
+        let alpha = |x: u32, y: u32| blurred.get_pixel(x, y).0[3];
+        assert!(alpha(center, center) < 255);
+        assert!(alpha(center, center) > alpha(center + 2, center));
+        assert!(alpha(center + 2, center) > alpha(center + 4, center));
+        assert_eq!(alpha(center + 3, center), alpha(center - 3, center));
+        assert_eq!(alpha(center, center + 3), alpha(center + 3, center));
+        assert_eq!(blurred.get_pixel(center + 1, center).0[..3], [200, 100, 0]);
+        let total: u32 = blurred.pixels().map(|p| u32::from(p.0[3])).sum();
+        assert!(total.abs_diff(255) < 20, "total alpha {total}");
+    }
+}
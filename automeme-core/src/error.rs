@@ -0,0 +1,171 @@
+//! Errors returned while loading and rendering templates.
+
+use crate::FontFace;
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Everything that can go wrong in automeme-core.
+#[derive(Debug)]
+pub enum AutomemeError {
+    /// No template exists with the requested name
+    TemplateNotFound(String),
+    /// A path could not be converted into a usable string
+    InvalidPath(PathBuf),
+    /// A file could not be opened or read
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// A template file could not be deserialized
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// An image file could not be decoded
+    ImageDecode {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    /// A font file could not be loaded
+    FontLoad { path: PathBuf, message: String },
+    /// A font has no face matching the one a template selects
+    FontFaceNotFound {
+        path: PathBuf,
+        face: FontFace,
+        available: Vec<String>,
+    },
+    /// A template names a font that does not exist, or no font at all
+    FontNotFound {
+        template: String,
+        font: Option<String>,
+        /// The template root the font was searched for in
+        path: PathBuf,
+    },
+    /// A text field has a position or size that cannot be rendered
+    InvalidGeometry {
+        template: String,
+        field: usize,
+        message: String,
+    },
+    /// A template extends a template that does not exist or that leads back
+    /// to itself, or the merged template is incomplete
+    InvalidExtends { template: String, message: String },
+    /// A template from a pack uses a path that leads outside of the pack
+    PathOutsideSource { template: String, path: String },
+    /// The text of a field does not fit even at its minimum size, and the
+    /// field's overflow policy is to fail
+    TextOverflow { field: usize, min_text_size: f32 },
+    /// A template could not be serialized
+    Serialize(serde_json::Error),
+    /// The template directory could not be watched for changes
+    #[cfg(feature = "watch")]
+    Watch(notify::Error),
+    /// A template pack is not an archive that can be read
+    #[cfg(feature = "packs")]
+    Archive { path: PathBuf, message: String },
+}
+
+impl fmt::Display for AutomemeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AutomemeError::TemplateNotFound(name) => write!(f, "Template '{name}' not found"),
+            AutomemeError::InvalidPath(path) => {
+                write!(f, "Path {} is not valid UTF-8", path.display())
+            }
+            AutomemeError::Io { path, source } => {
+                write!(f, "Failed to read file {}: {source}", path.display())
+            }
+            AutomemeError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "Failed to parse {} at line {line}, column {column}: {message}",
+                path.display()
+            ),
+            AutomemeError::ImageDecode { path, source } => {
+                write!(f, "Failed to decode image {}: {source}", path.display())
+            }
+            AutomemeError::FontLoad { path, message } => {
+                write!(f, "Failed to load font {}: {message}", path.display())
+            }
+            AutomemeError::FontFaceNotFound {
+                path,
+                face,
+                available,
+            } => write!(
+                f,
+                "Font {} has no face {face}, its faces are: {}",
+                path.display(),
+                available.join(", ")
+            ),
+            AutomemeError::FontNotFound {
+                template,
+                font: Some(font),
+                path,
+            } => write!(
+                f,
+                "Template '{template}' uses font '{font}', which could not be found in the fonts directory or the template root {}",
+                path.display()
+            ),
+            AutomemeError::FontNotFound {
+                template,
+                font: None,
+                ..
+            } => write!(f, "Template '{template}' does not specify a font"),
+            AutomemeError::InvalidGeometry {
+                template,
+                field,
+                message,
+            } => write!(
+                f,
+                "Template '{template}' has an invalid text field at index {field}: {message}"
+            ),
+            AutomemeError::InvalidExtends { template, message } => {
+                write!(f, "Template '{template}' has an invalid `extends`: {message}")
+            }
+            AutomemeError::PathOutsideSource { template, path } => write!(
+                f,
+                "Template '{template}' uses '{path}', which is outside of the pack it is in"
+            ),
+            AutomemeError::TextOverflow {
+                field,
+                min_text_size,
+            } => write!(
+                f,
+                "Text in field {field} does not fit even at the minimum size of {min_text_size}"
+            ),
+            AutomemeError::Serialize(source) => write!(f, "Failed to serialize template: {source}"),
+            #[cfg(feature = "watch")]
+            AutomemeError::Watch(source) => {
+                write!(f, "Failed to watch template directory: {source}")
+            }
+            #[cfg(feature = "packs")]
+            AutomemeError::Archive { path, message } => {
+                write!(
+                    f,
+                    "Failed to read template pack {}: {message}",
+                    path.display()
+                )
+            }
+        }
+    }
+}
+
+impl Error for AutomemeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AutomemeError::Io { source, .. } => Some(source),
+            AutomemeError::ImageDecode { source, .. } => Some(source),
+            AutomemeError::Serialize(source) => Some(source),
+            #[cfg(feature = "watch")]
+            AutomemeError::Watch(source) => Some(source),
+            _ => None,
+        }
+    }
+}
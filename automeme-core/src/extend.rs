@@ -0,0 +1,320 @@
+//! Templates based on other templates through the `extends` key.
+
+use crate::source::TemplateSource;
+use crate::store::parse_template;
+use crate::{AutomemeError, TemplateStore};
+use serde_json::{Map, Value};
+use std::path::{Component, Path, PathBuf};
+
+/// Keys holding a path relative to the template root.
+const PATH_KEYS: [&str; 2] = ["image_path", "font_path"];
+
+impl TemplateStore {
+    /// Merge a parsed template over every template it extends, if it extends
+    /// one. `source` is the source the template is in, and `chain` holds
+    /// every template already visited on the way here, ending with this one.
+    /// Returns the merged template and the path of every other template file
+    /// read.
+    ///
+    /// # Errors
+    /// Will return Err if any template file cannot be read or parsed, if a
+    /// template in the chain extends one that does not exist or leads back to
+    /// itself, or if a template that is not in a directory uses a path
+    /// outside of its source.
+    pub(crate) fn extend_template(
+        &self,
+        template_name: &str,
+        template_value: Value,
+        source: &TemplateSource,
+        chain: &mut Vec<String>,
+    ) -> Result<(Value, Vec<PathBuf>), AutomemeError> {
+        // A path outside of a pack would be read from the disk instead
+        if !source.is_directory() {
+            check_contained(template_name, &template_value)?;
+        }
+
+        let invalid = |message: String| AutomemeError::InvalidExtends {
+            template: template_name.to_owned(),
+            message,
+        };
+
+        let parent_name = match template_value.get("extends") {
+            None | Some(Value::Null) => return Ok((template_value, Vec::new())),
+            Some(Value::String(parent_name)) => parent_name,
+            Some(_) => return Err(invalid("extends must be a template name".to_owned())),
+        };
+        let parent_name = match self.resolve_template_name(parent_name) {
+            Ok(parent_name) => parent_name,
+            Err(AutomemeError::TemplateNotFound(_)) => {
+                return Err(invalid(format!("template '{parent_name}' does not exist")))
+            }
+            Err(e) => return Err(e),
+        };
+        if chain.contains(&parent_name) {
+            return Err(invalid(format!(
+                "it extends itself through {} -> {parent_name}",
+                chain.join(" -> ")
+            )));
+        }
+
+        chain.push(parent_name.clone());
+        let (parent_source, parent_file, text) = self.read_template_file(&parent_name)?;
+        let parent_value = parse_template(&parent_file, &text)?;
+        let (mut parent_value, parent_files) =
+            self.extend_template(&parent_name, parent_value, parent_source, chain)?;
+        let mut files = vec![parent_file];
+        files.extend(parent_files);
+
+        // Paths in the parent stay relative to its own root
+        if parent_source.root() != source.root() {
+            if let Value::Object(parent) = &mut parent_value {
+                self.rebase_template(parent, parent_source.root())?;
+            }
+        }
+
+        Ok((merge_templates(parent_value, template_value), files))
+    }
+
+    /// Point every path and font name in a template at the files under its
+    /// own root, so they still work once it is merged into a template from
+    /// another source. Font names that are not found under the root are
+    /// left as they are.
+    ///
+    /// # Errors
+    /// Will return Err if a rebased path is not valid UTF-8.
+    fn rebase_template(
+        &self,
+        template: &mut Map<String, Value>,
+        root: &Path,
+    ) -> Result<(), AutomemeError> {
+        let path_string = |file_path: PathBuf| {
+            file_path
+                .to_str()
+                .map(str::to_owned)
+                .ok_or_else(|| AutomemeError::InvalidPath(file_path.clone()))
+        };
+        let rebase_font = |font_name: &mut String| {
+            if let Some(font_path) = self.find_font(root, font_name) {
+                *font_name = path_string(font_path)?;
+            }
+            Ok::<_, AutomemeError>(())
+        };
+
+        for (key, value) in template.iter_mut() {
+            match (key.as_str(), value) {
+                (key, Value::String(relative_path)) if PATH_KEYS.contains(&key) => {
+                    *relative_path = path_string(root.join(&*relative_path))?;
+                }
+                ("font", Value::String(font_name)) => rebase_font(font_name)?,
+                ("fallback_fonts", Value::Array(font_names)) => {
+                    for font_name in font_names {
+                        if let Value::String(font_name) = font_name {
+                            rebase_font(font_name)?;
+                        }
+                    }
+                }
+                ("text_fields", Value::Array(text_fields)) => {
+                    for text_field in text_fields {
+                        if let Some(Value::String(font_name)) = text_field.get_mut("font") {
+                            rebase_font(font_name)?;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check that no path or font name in a template leads out of the root of its
+/// source, by being absolute or going up through `..`.
+///
+/// # Errors
+/// Will return Err with the first path that leads out of the root.
+fn check_contained(template_name: &str, template: &Value) -> Result<(), AutomemeError> {
+    let mut paths: Vec<&Value> = PATH_KEYS
+        .iter()
+        .chain(&["font"])
+        .filter_map(|key| template.get(key))
+        .collect();
+    if let Some(Value::Array(font_names)) = template.get("fallback_fonts") {
+        paths.extend(font_names);
+    }
+    if let Some(Value::Array(text_fields)) = template.get("text_fields") {
+        paths.extend(
+            text_fields
+                .iter()
+                .filter_map(|text_field| text_field.get("font")),
+        );
+    }
+    let is_contained = |path: &&str| {
+        Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    };
+    match paths
+        .into_iter()
+        .filter_map(Value::as_str)
+        .find(|path| !is_contained(path))
+    {
+        Some(path) => Err(AutomemeError::PathOutsideSource {
+            template: template_name.to_owned(),
+            path: path.to_owned(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Merge a template over the template it extends. Keys set in the template
+/// replace the same keys in its parent, except for text fields, which are
+/// merged one by one. A template setting `font` or `font_path` replaces
+/// whichever of the two the parent sets. Aliases belong to a single template
+/// and are never inherited.
+fn merge_templates(parent: Value, template: Value) -> Value {
+    match (parent, template) {
+        (Value::Object(mut merged), Value::Object(template)) => {
+            merged.remove("aliases");
+            for (key, other_key) in [("font", "font_path"), ("font_path", "font")] {
+                if template.contains_key(key) {
+                    merged.remove(other_key);
+                }
+            }
+            for (key, value) in template {
+                let value = match (key.as_str(), merged.remove(&key), value) {
+                    ("text_fields", Some(Value::Array(parent_fields)), Value::Array(fields)) => {
+                        merge_text_fields(parent_fields, fields)
+                    }
+                    (_, _, value) => value,
+                };
+                merged.insert(key, value);
+            }
+            Value::Object(merged)
+        }
+        (_, template) => template,
+    }
+}
+
+/// Merge each text field over the parent's text field at the same index, so
+/// a template can change only some properties of a field. An empty field
+/// keeps the parent's field as it is, and extra fields are added on the end.
+fn merge_text_fields(parent_fields: Vec<Value>, fields: Vec<Value>) -> Value {
+    let mut fields = fields.into_iter();
+    let mut merged: Vec<Value> = parent_fields
+        .into_iter()
+        .map(|parent_field| match (parent_field, fields.next()) {
+            (Value::Object(mut parent_field), Some(Value::Object(field))) => {
+                parent_field.extend(field);
+                Value::Object(parent_field)
+            }
+            (_, Some(field)) => field,
+            (parent_field, None) => parent_field,
+        })
+        .collect();
+    merged.extend(fields);
+    Value::Array(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{bundled_template, write_template, TEMPLATES};
+    use crate::{AutomemeError, TemplateStore};
+    use std::fs::{copy, create_dir, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_extends_merges_fields() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        write_template(root, "base", &bundled_template("yelling-at-cat"));
+        write(
+            root.join("variant.json"),
+            r#"{
+                "extends": "base",
+                "text_fields": [
+                    {},
+                    { "text": "changed", "text_color": [255, 0, 0] },
+                    {
+                        "text": "", "uppercase": false,
+                        "start": [50, 100], "end": [1992, 400],
+                        "text_size": 100, "text_color": [0, 0, 0]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let store = TemplateStore::new(root);
+        let template = store.get_template_from_disk("variant").unwrap();
+        assert_eq!(
+            template.metadata.title.as_deref(),
+            Some("Woman Yelling at Cat")
+        );
+        assert!(template.metadata.aliases.is_empty());
+        assert_eq!(template.text_fields.len(), 3);
+        assert_eq!(template.text_fields[0].start, [50, 1500]);
+        assert_eq!(template.text_fields[0].border_color, Some([0, 0, 0]));
+        assert_eq!(template.text_fields[1].text, "changed");
+        assert_eq!(template.text_fields[1].text_color, [255, 0, 0]);
+        assert_eq!(template.text_fields[1].start, [2092, 1500]);
+        assert_eq!(template.text_fields[2].start, [50, 100]);
+
+        // Editing the base template should reload the variant too
+        let files = store.get_template_files("variant").unwrap();
+        assert!(files.contains(&root.join("base.json")));
+    }
+
+    #[test]
+    fn test_extends_replaces_font_path_with_font() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        create_dir(root.join("fonts")).unwrap();
+        copy(
+            format!("{TEMPLATES}/fonts/Gabarito-Bold.ttf"),
+            root.join("fonts/Gabarito-Bold.ttf"),
+        )
+        .unwrap();
+        write_template(root, "base", &bundled_template("yelling-at-cat"));
+        write(
+            root.join("variant.json"),
+            r#"{ "extends": "base", "font": "Gabarito-Bold" }"#,
+        )
+        .unwrap();
+
+        // The base template's font_path would otherwise win over the font
+        let store = TemplateStore::new(root);
+        assert!(store
+            .get_json_from_disk("variant")
+            .unwrap()
+            .font_path
+            .is_none());
+        let files = store.get_template_files("variant").unwrap();
+        assert!(files.contains(&root.join("fonts/Gabarito-Bold.ttf")));
+        assert!(!files
+            .iter()
+            .any(|file_path| file_path.ends_with("Anton-Regular.ttf")));
+        assert!(store.validate_template("variant").warnings.is_empty());
+    }
+
+    #[test]
+    fn test_extends_cycle() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        write(root.join("a.json"), r#"{ "extends": "b" }"#).unwrap();
+        write(root.join("b.json"), r#"{ "extends": "a" }"#).unwrap();
+        write(root.join("lonely.json"), r#"{ "extends": "nobody" }"#).unwrap();
+
+        let store = TemplateStore::new(root);
+        let Err(AutomemeError::InvalidExtends { message, .. }) = store.get_json_from_disk("a")
+        else {
+            panic!("a cycle of templates should not load");
+        };
+        assert!(message.contains("a -> b -> a"));
+        let error = store.get_json_from_disk("lonely").unwrap_err();
+        assert!(matches!(error, AutomemeError::InvalidExtends { .. }));
+        assert_eq!(
+            error.to_string(),
+            "Template 'lonely' has an invalid `extends`: template 'nobody' does not exist"
+        );
+    }
+}
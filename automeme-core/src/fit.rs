@@ -0,0 +1,772 @@
+//! Fitting the text of a field inside its bounds.
+
+use crate::{
+    split_text_by_font, OverflowPolicy, SpanStyle, StyledText, TextAlign, TextField,
+    VerticalTextAlign,
+};
+use fontdue::layout::{
+    CoordinateSystem, GlyphPosition, HorizontalAlign, Layout, LayoutSettings, TextStyle,
+    VerticalAlign, WrapStyle,
+};
+use fontdue::Font;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// Smallest size text is reduced to in fields that do not set
+/// `min_text_size`.
+pub const DEFAULT_MIN_TEXT_SIZE: f32 = 1.0;
+
+/// Steps text is shrunk by to find the largest size that fits.
+const FIT_PRECISION: f32 = 0.5;
+
+/// The text of a field laid out to fit in the field.
+pub struct FittedText {
+    /// The laid out lines, in a canvas the size of the field, or of the text
+    /// where it overflows the field
+    pub layout: Layout<SpanStyle>,
+    /// Where each glyph of the layout is drawn, moved apart by the field's
+    /// letter spacing
+    pub glyphs: Vec<GlyphPosition<SpanStyle>>,
+    /// Width and height of the canvas the glyphs are drawn in
+    pub canvas_size: (u32, u32),
+    /// The size the text was laid out at
+    pub text_size: f32,
+    /// Whether the end of the text was cut off to fit
+    pub truncated: bool,
+    /// Whether words were broken with hyphens to fit
+    pub hyphenated: bool,
+}
+
+impl FittedText {
+    /// The box around the laid out text as `[left, top, right, bottom]`, from
+    /// the first character drawn on any line to the last, and from the top of
+    /// the first line to the bottom of the last. None if nothing is drawn.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn text_bounds(&self) -> Option<[f32; 4]> {
+        let drawn = self.glyphs.iter().filter(|glyph| glyph.width > 0);
+        let left = drawn.clone().map(|glyph| glyph.x).reduce(f32::min)?;
+        let right = drawn
+            .map(|glyph| glyph.x + glyph.width as f32)
+            .reduce(f32::max)?;
+        let lines = self.layout.lines()?;
+        let (first, last) = (lines.first()?, lines.last()?);
+        Some([
+            left,
+            first.baseline_y - first.max_ascent,
+            right,
+            last.baseline_y - last.min_descent,
+        ])
+    }
+}
+
+/// Lays out the text of a field inside the field bounds, shrinking the text
+/// to the largest size where every line fits, without breaking inside words.
+/// Fields that allow it break long words with hyphens rather than shrinking
+/// below their minimum size. Text that does not fit even then is handled by
+/// the field's overflow policy. Returns None if the text does not fit and the
+/// policy is to fail. Line height and letter spacing are taken into account
+/// when breaking lines and measuring the text.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn layout_text_field(text_field: &TextField, fonts: &[Arc<Font>]) -> Option<FittedText> {
+    // Get field width & height
+    let field_width = text_field.end[0].saturating_sub(text_field.start[0]) as f32;
+    let field_height = text_field.end[1].saturating_sub(text_field.start[1]) as f32;
+
+    // Take out markup and optionally convert to uppercase. Characters added
+    // while fitting the text keep the style of the character before them.
+    let text = text_field.styled_text();
+
+    let layout_text = |text: &StyledText, text_size: f32, canvas_height: f32| {
+        layout_text(
+            text_field,
+            text,
+            text_size,
+            (field_width, canvas_height),
+            fonts,
+        )
+    };
+    // Lay out text at a size, or None if it is too tall for the field or a
+    // word is too wide for it. With hyphens, words too wide are broken up
+    // instead.
+    let try_layout = |text: &StyledText, text_size: f32, hyphenate: bool| {
+        let spacing = letter_spacing(text_field, text_size);
+        let text = if hyphenate {
+            Cow::Owned(hyphenate_words(
+                text,
+                text_size,
+                spacing,
+                field_width,
+                fonts,
+            ))
+        } else if widest_word(text.text(), text_size, spacing, fonts) > field_width {
+            return None;
+        } else {
+            Cow::Borrowed(text)
+        };
+        let layout = layout_text(&text, text_size, field_height);
+        (layout.height() <= field_height).then_some(layout)
+    };
+    let fitted =
+        |layout: Layout<SpanStyle>, canvas_width: f32, text_size, truncated, hyphenated| {
+            FittedText {
+                glyphs: space_glyphs(&layout, text_field, text_size, canvas_width, fonts),
+                canvas_size: (
+                    canvas_width.ceil() as u32,
+                    layout.settings().max_height.unwrap_or_default().ceil() as u32,
+                ),
+                layout,
+                text_size,
+                truncated,
+                hyphenated,
+            }
+        };
+
+    // Search for the largest size that fits, if any does, in steps down from
+    // the full size. Most text fits at its full size.
+    let min_text_size = text_field.min_text_size();
+    let size_at =
+        |step: usize| (text_field.text_size - step as f32 * FIT_PRECISION).max(min_text_size);
+    let fit_size = |hyphenate: bool| {
+        if let Some(layout) = try_layout(&text, text_field.text_size, hyphenate) {
+            return Some((layout, text_field.text_size));
+        }
+        let steps = ((text_field.text_size - min_text_size) / FIT_PRECISION).ceil() as usize;
+        let mut fitting = (steps, try_layout(&text, min_text_size, hyphenate)?);
+        let mut too_big = 0;
+        while fitting.0 - too_big > 1 {
+            let step = too_big + (fitting.0 - too_big) / 2;
+            match try_layout(&text, size_at(step), hyphenate) {
+                Some(layout) => fitting = (step, layout),
+                None => too_big = step,
+            }
+        }
+        Some((fitting.1, size_at(fitting.0)))
+    };
+    if let Some((layout, text_size)) = fit_size(false) {
+        return Some(fitted(layout, field_width, text_size, false, false));
+    }
+
+    // Break up long words before giving up on fitting the text
+    let hyphenate = text_field.hyphenate;
+    if hyphenate {
+        if let Some((layout, text_size)) = fit_size(true) {
+            return Some(fitted(layout, field_width, text_size, false, true));
+        }
+    }
+
+    match text_field.overflow {
+        OverflowPolicy::Error => None,
+        OverflowPolicy::Overflow => {
+            let (layout, canvas_width) =
+                layout_overflowing(text_field, &text, min_text_size, hyphenate, fonts);
+            Some(fitted(
+                layout,
+                canvas_width,
+                min_text_size,
+                false,
+                hyphenate,
+            ))
+        }
+        OverflowPolicy::Ellipsis => {
+            // Cut the text off, keeping the ellipsis even if nothing else fits
+            let layout = truncate_to_fit(&text, fonts, |text| {
+                try_layout(text, min_text_size, hyphenate)
+            })
+            .unwrap_or_else(|| {
+                layout_text(&truncate(&text, 0, fonts), min_text_size, field_height)
+            });
+            Some(fitted(layout, field_width, min_text_size, true, hyphenate))
+        }
+    }
+}
+
+/// Lay out text that does not fit in its field at a size, growing the canvas
+/// to hold every line. Lines still break at the width of the field, and words
+/// too wide for it are kept whole on lines of their own. Returns the layout
+/// and the width of its canvas.
+#[allow(clippy::cast_precision_loss)]
+fn layout_overflowing(
+    text_field: &TextField,
+    text: &StyledText,
+    text_size: f32,
+    hyphenate: bool,
+    fonts: &[Arc<Font>],
+) -> (Layout<SpanStyle>, f32) {
+    let field_width = text_field.end[0].saturating_sub(text_field.start[0]) as f32;
+    let field_height = text_field.end[1].saturating_sub(text_field.start[1]) as f32;
+    let spacing = letter_spacing(text_field, text_size);
+    let width = |line: &str| text_width(line, text_size, spacing, fonts);
+    let text = if hyphenate {
+        Cow::Owned(hyphenate_words(
+            text,
+            text_size,
+            spacing,
+            field_width,
+            fonts,
+        ))
+    } else {
+        Cow::Borrowed(text)
+    };
+    let text = break_lines(&text, field_width, width);
+    let canvas_width = text
+        .text()
+        .lines()
+        .map(|line| width(line.trim_end()))
+        .fold(field_width, f32::max)
+        .ceil();
+    let layout_at = |canvas_height: f32| {
+        layout_text(
+            text_field,
+            &text,
+            text_size,
+            (canvas_width, canvas_height),
+            fonts,
+        )
+    };
+    let layout = layout_at(layout_at(field_height).height().ceil());
+    (layout, canvas_width)
+}
+
+/// Lay out some text of a field one run per style and font, in a canvas of
+/// the given width and height. The layout can't space letters out, so spaced
+/// text has its lines broken beforehand and is aligned after spacing it out
+/// instead.
+fn layout_text(
+    text_field: &TextField,
+    text: &StyledText,
+    text_size: f32,
+    (canvas_width, canvas_height): (f32, f32),
+    fonts: &[Arc<Font>],
+) -> Layout<SpanStyle> {
+    let spacing = letter_spacing(text_field, text_size);
+    let mut settings = layout_settings(text_field, canvas_width, canvas_height);
+    let text = if spacing == 0.0 {
+        Cow::Borrowed(text)
+    } else {
+        settings.max_width = Some(f32::MAX);
+        settings.horizontal_align = HorizontalAlign::Left;
+        Cow::Owned(break_lines(text, canvas_width, |line| {
+            text_width(line, text_size, spacing, fonts)
+        }))
+    };
+    let mut layout = Layout::new(CoordinateSystem::PositiveYDown);
+    layout.reset(&settings);
+    for (span, style) in text.runs() {
+        for (run, font_index) in split_text_by_font(span, fonts) {
+            layout.append(
+                fonts,
+                &TextStyle::with_user_data(run, text_size, font_index, style),
+            );
+        }
+    }
+    layout
+}
+
+/// The letter spacing of a field for text at a size, as it shrinks along with
+/// the text.
+fn letter_spacing(text_field: &TextField, text_size: f32) -> f32 {
+    if text_field.letter_spacing == 0.0 {
+        0.0
+    } else {
+        text_field.letter_spacing * text_size / text_field.text_size
+    }
+}
+
+/// Settings to lay out the text of a field in a canvas of the given size.
+fn layout_settings(text_field: &TextField, width: f32, height: f32) -> LayoutSettings {
+    LayoutSettings {
+        x: 0.0,
+        y: 0.0,
+        max_height: Some(height),
+        max_width: Some(width),
+        horizontal_align: match text_field.align {
+            TextAlign::Left => HorizontalAlign::Left,
+            TextAlign::Center => HorizontalAlign::Center,
+            TextAlign::Right => HorizontalAlign::Right,
+        },
+        vertical_align: match text_field.valign {
+            VerticalTextAlign::Top => VerticalAlign::Top,
+            VerticalTextAlign::Middle => VerticalAlign::Middle,
+            VerticalTextAlign::Bottom => VerticalAlign::Bottom,
+        },
+        wrap_style: WrapStyle::Word,
+        line_height: text_field.line_height.unwrap_or(1.0),
+        ..Default::default()
+    }
+}
+
+/// Break text into lines that each fit in a width, between words, by adding
+/// line breaks after the spaces where lines end. Words too wide for a line
+/// get a line of their own.
+fn break_lines(text: &StyledText, max_width: f32, width: impl Fn(&str) -> f32) -> StyledText {
+    let mut broken = StyledText::default();
+    let mut line_start = 0;
+    for piece in text.split_inclusive(|c: char| c.is_whitespace() || c == '-') {
+        let line = &broken.text()[line_start..];
+        if !line.trim().is_empty()
+            && width(format!("{line}{}", piece.text()).trim_end()) > max_width
+        {
+            broken.push_added("\n");
+            line_start = broken.text().len();
+        }
+        broken.push_styled(&piece);
+        if piece.text().ends_with('\n') {
+            line_start = broken.text().len();
+        }
+    }
+    broken
+}
+
+/// Positions of the glyphs of a layout with the field's letter spacing added
+/// between the characters of each line, and each line aligned in a canvas of
+/// the given width again. Spaced text is laid out aligned left.
+#[allow(clippy::cast_precision_loss)]
+fn space_glyphs(
+    layout: &Layout<SpanStyle>,
+    text_field: &TextField,
+    text_size: f32,
+    canvas_width: f32,
+    fonts: &[Arc<Font>],
+) -> Vec<GlyphPosition<SpanStyle>> {
+    let mut glyphs = layout.glyphs().clone();
+    let spacing = letter_spacing(text_field, text_size);
+    if spacing == 0.0 {
+        return glyphs;
+    }
+    let align = match text_field.align {
+        TextAlign::Left => 0.0,
+        TextAlign::Center => 0.5,
+        TextAlign::Right => 1.0,
+    };
+    for line in layout.lines().into_iter().flatten() {
+        let line_glyphs = &mut glyphs[line.glyph_start..=line.glyph_end];
+
+        // Measure up to the end of the last character drawn, leaving out the
+        // spaces lines are broken after
+        let mut pen = 0.0;
+        let mut line_width: f32 = 0.0;
+        for glyph in line_glyphs.iter() {
+            let advance = fonts[glyph.font_index]
+                .metrics_indexed(glyph.key.glyph_index, glyph.key.px)
+                .advance_width
+                .ceil();
+            if !glyph.parent.is_whitespace() && !glyph.parent.is_control() {
+                line_width = line_width.max(pen + advance);
+            }
+            pen += advance + spacing;
+        }
+        let offset = ((canvas_width - line_width) * align).floor();
+        for (index, glyph) in line_glyphs.iter_mut().enumerate() {
+            glyph.x += (offset + spacing * index as f32).round();
+        }
+    }
+    glyphs
+}
+
+/// Cut off the end of some text and add an ellipsis, keeping as many
+/// characters as still fit. Returns None if not even the ellipsis fits alone.
+fn truncate_to_fit(
+    text: &StyledText,
+    fonts: &[Arc<Font>],
+    try_layout: impl Fn(&StyledText) -> Option<Layout<SpanStyle>>,
+) -> Option<Layout<SpanStyle>> {
+    let (mut fitting, mut too_long) = (0, text.text().chars().count());
+    while too_long - fitting > 1 {
+        let count = fitting + (too_long - fitting) / 2;
+        if try_layout(&truncate(text, count, fonts)).is_some() {
+            fitting = count;
+        } else {
+            too_long = count;
+        }
+    }
+    try_layout(&truncate(text, fitting, fonts))
+}
+
+/// Keep the first characters of some text followed by an ellipsis, which is a
+/// single character if any of the fonts has it.
+fn truncate(text: &StyledText, count: usize, fonts: &[Arc<Font>]) -> StyledText {
+    let ellipsis = if fonts.iter().any(|font| font.lookup_glyph_index('…') != 0) {
+        "…"
+    } else {
+        "..."
+    };
+    let mut kept: Vec<(char, SpanStyle)> = text.chars().take(count).collect();
+    while kept.last().is_some_and(|(c, _)| c.is_whitespace()) {
+        kept.pop();
+    }
+    let mut truncated = StyledText::default();
+    for (c, style) in kept {
+        truncated.push(c, style);
+    }
+    truncated.push_added(ellipsis);
+    truncated
+}
+
+/// The width of text on a single line, adding up the same rounded advances
+/// the layout uses and `spacing` between each character.
+#[allow(clippy::cast_precision_loss)]
+fn text_width(text: &str, text_size: f32, spacing: f32, fonts: &[Arc<Font>]) -> f32 {
+    let gaps = text.chars().count().saturating_sub(1);
+    split_text_by_font(text, fonts)
+        .into_iter()
+        .flat_map(|(run, font_index)| {
+            run.chars()
+                .map(move |c| fonts[font_index].metrics(c, text_size).advance_width.ceil())
+        })
+        .sum::<f32>()
+        + spacing * gaps as f32
+}
+
+/// Split text into the pieces lines can't break inside of: words, which can
+/// also break after a hyphen.
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split_whitespace()
+        .flat_map(|word| word.split_inclusive('-'))
+}
+
+/// The width of the widest word in some text.
+fn widest_word(text: &str, text_size: f32, spacing: f32, fonts: &[Arc<Font>]) -> f32 {
+    words(text)
+        .map(|word| text_width(word, text_size, spacing, fonts))
+        .fold(0.0, f32::max)
+}
+
+/// Break every word too wide to fit on a line into pieces that each fit with a
+/// hyphen after them, moving each following piece onto a new line.
+fn hyphenate_words(
+    text: &StyledText,
+    text_size: f32,
+    spacing: f32,
+    max_width: f32,
+    fonts: &[Arc<Font>],
+) -> StyledText {
+    let hyphen_width = text_width("-", text_size, spacing, fonts);
+    let mut hyphenated = StyledText::default();
+    for piece in text.split_inclusive(|c: char| c.is_whitespace() || c == '-') {
+        if text_width(piece.text().trim_end(), text_size, spacing, fonts) <= max_width {
+            hyphenated.push_styled(&piece);
+            continue;
+        }
+        // Every line holds at least one character, even if it is too wide.
+        // Line widths include the spacing after their last character.
+        let mut line_width = 0.0;
+        let mut line_start = true;
+        for (c, style) in piece.chars() {
+            let char_width = text_width(&c.to_string(), text_size, spacing, fonts) + spacing;
+            if !line_start
+                && !c.is_whitespace()
+                && line_width + char_width + hyphen_width > max_width
+            {
+                hyphenated.push_added("-\n");
+                line_width = 0.0;
+            }
+            hyphenated.push(c, style);
+            line_width += char_width;
+            line_start = false;
+        }
+    }
+    hyphenated
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::TEMPLATES;
+    use crate::{
+        layout_text_field, render_template, AutomemeError, FittedText, OverflowPolicy, SpanStyle,
+        Template, TemplateStore, TextAlign, TextField, DEFAULT_MIN_TEXT_SIZE,
+    };
+
+    const LONG_TEXT: &str = "this text is far too long to fit in such a small field \
+        without shrinking a lot, and it goes on and on for quite a while longer still";
+
+    /// The bundled pikachu template, and a copy of the field the tests lay out.
+    fn pikachu_field() -> (Template, TextField) {
+        let template = TemplateStore::new(TEMPLATES)
+            .get_template_from_disk("pikachu")
+            .unwrap();
+        let text_field = template.text_fields[0].clone();
+        (template, text_field)
+    }
+
+    /// The width and height of a field.
+    #[allow(clippy::cast_precision_loss)]
+    fn field_size(text_field: &TextField) -> (f32, f32) {
+        (
+            (text_field.end[0] - text_field.start[0]) as f32,
+            (text_field.end[1] - text_field.start[1]) as f32,
+        )
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss, clippy::float_cmp)]
+    fn test_fit_finds_largest_size() {
+        let (template, mut text_field) = pikachu_field();
+        let fonts = template.fonts();
+        let (_, field_height) = field_size(&text_field);
+
+        // Short text keeps its size, long text shrinks to just fit
+        text_field.text = "short".to_owned();
+        text_field.text_size = 100.0;
+        let fitted = layout_text_field(&text_field, &fonts).unwrap();
+        assert_eq!(fitted.text_size, text_field.text_size);
+        text_field.text = LONG_TEXT.to_owned();
+        let fitted = layout_text_field(&text_field, &fonts).unwrap();
+        assert!(fitted.text_size < text_field.text_size);
+        assert!(fitted.layout.height() <= field_height);
+        assert!(!fitted.truncated);
+        let mut larger = text_field.clone();
+        larger.text_size = fitted.text_size + 1.0;
+        larger.min_text_size = Some(fitted.text_size + 1.0);
+        assert!(layout_text_field(&larger, &fonts).unwrap().truncated);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn test_min_text_size_is_clamped() {
+        let (template, mut text_field) = pikachu_field();
+        let fonts = template.fonts();
+        text_field.text = LONG_TEXT.repeat(20);
+
+        // Text shrunk to a size of zero or less could not be drawn
+        for min_text_size in [0.0, -10.0] {
+            text_field.min_text_size = Some(min_text_size);
+            assert_eq!(text_field.min_text_size(), DEFAULT_MIN_TEXT_SIZE);
+            let fitted = layout_text_field(&text_field, &fonts).unwrap();
+            assert!(fitted.text_size >= DEFAULT_MIN_TEXT_SIZE);
+        }
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss, clippy::float_cmp)]
+    fn test_overflow_policies() {
+        let (mut template, mut text_field) = pikachu_field();
+        let fonts = template.fonts();
+        let (_, field_height) = field_size(&text_field);
+        text_field.text = LONG_TEXT.to_owned();
+        text_field.min_text_size = Some(100.0);
+
+        // The ellipsis cuts the text off at the minimum size
+        let fitted = layout_text_field(&text_field, &fonts).unwrap();
+        assert!(fitted.truncated);
+        assert_eq!(fitted.text_size, 100.0);
+        assert!(fitted.layout.height() <= field_height);
+        let last = fitted.layout.glyphs().last().unwrap().parent;
+        assert!(last == '…' || last == '.');
+
+        // Overflowing text keeps every character in a taller canvas
+        text_field.overflow = OverflowPolicy::Overflow;
+        let fitted = layout_text_field(&text_field, &fonts).unwrap();
+        assert!(!fitted.truncated);
+        assert!(fitted.layout.settings().max_height.unwrap() > field_height);
+        assert!(fitted.layout.height() > field_height);
+
+        // Failing stops the whole template from rendering
+        text_field.overflow = OverflowPolicy::Error;
+        assert!(layout_text_field(&text_field, &fonts).is_none());
+        template.text_fields[0] = text_field;
+        assert!(matches!(
+            render_template(template),
+            Err(AutomemeError::TextOverflow { field: 0, .. })
+        ));
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn test_overflow_keeps_wide_words_whole() {
+        let (mut template, mut text_field) = pikachu_field();
+        let fonts = template.fonts();
+        let (field_width, _) = field_size(&text_field);
+        text_field.text = "so supercalifragilisticexpialidocious".to_owned();
+        text_field.min_text_size = Some(text_field.text_size);
+        text_field.overflow = OverflowPolicy::Overflow;
+
+        // The word gets a line of its own in a canvas wide enough to hold it,
+        // and the rest of the text still wraps at the width of the field
+        for align in [TextAlign::Left, TextAlign::Center, TextAlign::Right] {
+            text_field.align = align;
+            let fitted = layout_text_field(&text_field, &fonts).unwrap();
+            let canvas_width = fitted.canvas_size.0 as f32;
+            assert!(canvas_width > field_width);
+            let lines = fitted.layout.lines().unwrap();
+            assert_eq!(lines.len(), 2);
+            let word = &fitted.glyphs[lines[1].glyph_start..=lines[1].glyph_end];
+            assert_eq!(
+                word.iter().map(|glyph| glyph.parent).collect::<String>(),
+                "SUPERCALIFRAGILISTICEXPIALIDOCIOUS"
+            );
+            let [left, _, right, _] = fitted.text_bounds().unwrap();
+            assert!(left >= 0.0 && right <= canvas_width);
+        }
+        template.text_fields[0] = text_field;
+        render_template(template).unwrap();
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn test_fit_to_width() {
+        let (template, mut text_field) = pikachu_field();
+        let fonts = template.fonts();
+        let (field_width, _) = field_size(&text_field);
+        let letters = |fitted: &FittedText| {
+            let glyphs = fitted.layout.glyphs();
+            glyphs
+                .iter()
+                .filter(|glyph| glyph.parent.is_alphabetic())
+                .count()
+        };
+
+        // A word too wide for the field shrinks rather than breaking
+        text_field.text = "supercalifragilisticexpialidocious".to_owned();
+        text_field.text_size = 100.0;
+        let fitted = layout_text_field(&text_field, &fonts).unwrap();
+        assert!(fitted.text_size < text_field.text_size);
+        assert!(!fitted.hyphenated);
+        assert_eq!(fitted.layout.lines().unwrap().len(), 1);
+        for glyph in fitted.layout.glyphs() {
+            assert!(glyph.x + glyph.width as f32 <= field_width);
+        }
+
+        // Below the minimum size it breaks with hyphens instead, if allowed
+        text_field.end[1] = text_field.start[1] + 400;
+        text_field.min_text_size = Some(80.0);
+        text_field.overflow = OverflowPolicy::Error;
+        assert!(layout_text_field(&text_field, &fonts).is_none());
+        text_field.hyphenate = true;
+        let fitted = layout_text_field(&text_field, &fonts).unwrap();
+        assert!(fitted.hyphenated);
+        assert!(fitted.text_size >= 80.0);
+        assert_eq!(letters(&fitted), text_field.text.len());
+        let glyphs = fitted.layout.glyphs();
+        let lines = fitted.layout.lines().unwrap();
+        assert!(lines.len() > 1);
+        for line in &lines[..lines.len() - 1] {
+            let line_text: String = glyphs[line.glyph_start..=line.glyph_end]
+                .iter()
+                .map(|glyph| glyph.parent)
+                .collect();
+            assert!(line_text.trim_end().ends_with('-'));
+        }
+    }
+
+    #[test]
+    fn test_plain_text_keeps_markup_characters() {
+        let (template, mut text_field) = pikachu_field();
+        let fonts = template.fonts();
+        text_field.text = "C:\\Users 2*3*4 ~approx~ {red:x}".to_owned();
+        text_field.uppercase = false;
+
+        // Without markup every character is drawn as written, in one style
+        let fitted = layout_text_field(&text_field, &fonts).unwrap();
+        let drawn: String = fitted.glyphs.iter().map(|glyph| glyph.parent).collect();
+        assert_eq!(drawn, text_field.text);
+        assert!(fitted
+            .glyphs
+            .iter()
+            .all(|glyph| glyph.user_data == SpanStyle::default()));
+
+        // With markup the same text is styled
+        text_field.markup = true;
+        let fitted = layout_text_field(&text_field, &fonts).unwrap();
+        let drawn: String = fitted.glyphs.iter().map(|glyph| glyph.parent).collect();
+        assert_eq!(drawn, "C:\\Users 234 approx x");
+    }
+
+    #[test]
+    fn test_hyphenation_keeps_styles() {
+        let (template, mut text_field) = pikachu_field();
+        let fonts = template.fonts();
+        let (red_word, blue_word) = (
+            "supercalifragilisticexpialidocious",
+            "antidisestablishmentarianism",
+        );
+        text_field.text = format!("{{red:{red_word}}}-{{blue:{blue_word}}}");
+        text_field.markup = true;
+        text_field.uppercase = false;
+        text_field.text_size = 100.0;
+        text_field.min_text_size = Some(80.0);
+        text_field.end[1] = text_field.start[1] + 800;
+        text_field.hyphenate = true;
+        let fitted = layout_text_field(&text_field, &fonts).unwrap();
+        assert!(fitted.hyphenated && !fitted.truncated);
+
+        // Every letter keeps its own style wherever hyphens are added, and
+        // added hyphens take the style of the letter before them
+        let red = Some([230, 30, 30]);
+        let blue = Some([30, 90, 230]);
+        let glyphs = &fitted.glyphs;
+        let letter_colors: Vec<_> = glyphs
+            .iter()
+            .filter(|glyph| glyph.parent.is_alphabetic())
+            .map(|glyph| glyph.user_data.color)
+            .collect();
+        let mut expected = vec![red; red_word.len()];
+        expected.extend(vec![blue; blue_word.len()]);
+        assert_eq!(letter_colors, expected);
+        let mut written_hyphens = 0;
+        for (index, glyph) in glyphs.iter().enumerate() {
+            if glyph.parent != '-' {
+                continue;
+            }
+            let before = glyphs[index - 1].user_data.color;
+            if glyph.user_data.color.is_none() {
+                assert_eq!(before, red);
+                written_hyphens += 1;
+            } else {
+                assert_eq!(glyph.user_data.color, before);
+            }
+        }
+        assert_eq!(written_hyphens, 1);
+        assert!(glyphs.iter().filter(|glyph| glyph.parent == '-').count() > 1);
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss, clippy::float_cmp)]
+    fn test_spacing() {
+        let (template, mut text_field) = pikachu_field();
+        let fonts = template.fonts();
+        let (field_width, _) = field_size(&text_field);
+        text_field.text = "lots of room".to_owned();
+        text_field.text_size = 40.0;
+        text_field.end[1] = text_field.start[1] + 400;
+        let plain = layout_text_field(&text_field, &fonts).unwrap();
+        let line_count = |fitted: &FittedText| fitted.layout.lines().unwrap().len();
+        let ink_width = |fitted: &FittedText| {
+            let drawn = fitted.glyphs.iter().filter(|glyph| glyph.width > 0);
+            let left = drawn.clone().map(|glyph| glyph.x).fold(f32::MAX, f32::min);
+            let right = drawn
+                .map(|glyph| glyph.x + glyph.width as f32)
+                .fold(f32::MIN, f32::max);
+            assert!(left >= 0.0 && right <= field_width);
+            // Centered text has as much room on either side
+            assert!((left - (field_width - right)).abs() < 4.0);
+            right - left
+        };
+
+        // Taller lines take more room
+        let mut tall = text_field.clone();
+        tall.text = "two\nlines".to_owned();
+        let short_height = layout_text_field(&tall, &fonts).unwrap().layout.height();
+        tall.line_height = Some(1.5);
+        assert!(layout_text_field(&tall, &fonts).unwrap().layout.height() > short_height);
+
+        // Letters spread out or pull together, keeping the text centered
+        text_field.letter_spacing = 4.0;
+        let spread = layout_text_field(&text_field, &fonts).unwrap();
+        assert_eq!(spread.text_size, plain.text_size);
+        assert!(ink_width(&spread) > ink_width(&plain) + 30.0);
+        text_field.letter_spacing = -2.0;
+        let tight = layout_text_field(&text_field, &fonts).unwrap();
+        assert!(ink_width(&tight) < ink_width(&plain) - 15.0);
+
+        // Lines break sooner when letters spread out
+        text_field.letter_spacing = 100.0;
+        let wide = layout_text_field(&text_field, &fonts).unwrap();
+        assert_eq!(line_count(&plain), 1);
+        assert!(line_count(&wide) > 1);
+        for glyph in wide.glyphs.iter().filter(|glyph| glyph.width > 0) {
+            assert!(glyph.x >= 0.0 && glyph.x + glyph.width as f32 <= field_width);
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! A registry of parsed fonts, so each font file is only loaded once no matter
+//! how many templates use it.
+
+use crate::source::{disk, TemplateSource};
+use crate::{AutomemeError, FontFace, FONT_GEOMETRY_SCALE};
+use fontdue::{Font, FontSettings};
+use std::collections::HashMap;
+use std::fs::canonicalize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// Fonts keyed by the canonical path they were loaded from and the face
+/// selected from the file.
+#[derive(Debug, Default)]
+pub struct FontRegistry {
+    fonts: RwLock<HashMap<(PathBuf, FontFace), Arc<Font>>>,
+}
+
+impl FontRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        FontRegistry::default()
+    }
+
+    /// Get the key a font file is stored under.
+    fn key(font_path: &Path) -> PathBuf {
+        canonicalize(font_path).unwrap_or(font_path.to_path_buf())
+    }
+
+    /// Get a font from the registry, loading it from the disk if it has not
+    /// been loaded before. Collections use their first face.
+    ///
+    /// # Errors
+    /// Will return Err if the font file cannot be opened, read, or loaded.
+    pub fn get_font(&self, font_path: &Path) -> Result<Arc<Font>, AutomemeError> {
+        self.get_font_face(font_path, &FontFace::default())
+    }
+
+    /// Get a face of a font from the registry, loading it from the disk if it
+    /// has not been loaded before.
+    ///
+    /// # Errors
+    /// Will return Err if the font file cannot be opened, read, or loaded, or
+    /// has no such face.
+    pub fn get_font_face(
+        &self,
+        font_path: &Path,
+        face: &FontFace,
+    ) -> Result<Arc<Font>, AutomemeError> {
+        self.get_font_from(font_path, face, disk())
+    }
+
+    /// Get a face of a font from the registry, loading it from the given
+    /// source if it has not been loaded before.
+    ///
+    /// # Errors
+    /// Will return Err if the font file cannot be opened, read, or loaded, or
+    /// has no such face.
+    pub(crate) fn get_font_from(
+        &self,
+        font_path: &Path,
+        face: &FontFace,
+        source: &TemplateSource,
+    ) -> Result<Arc<Font>, AutomemeError> {
+        let key = (FontRegistry::key(font_path), face.clone());
+        if let Some(font) = self
+            .fonts
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+        {
+            return Ok(Arc::clone(font));
+        }
+
+        // Open and decode font
+        let font_bytes = source.read(font_path)?;
+        let collection_index =
+            find_face(&font_bytes, face).ok_or_else(|| AutomemeError::FontFaceNotFound {
+                path: font_path.to_path_buf(),
+                face: face.clone(),
+                available: face_names(&font_bytes),
+            })?;
+        let font = Font::from_bytes(
+            font_bytes,
+            FontSettings {
+                collection_index,
+                scale: FONT_GEOMETRY_SCALE,
+            },
+        )
+        .map_err(|message| AutomemeError::FontLoad {
+            path: font_path.to_path_buf(),
+            message: message.to_owned(),
+        })?;
+
+        // Another thread may have loaded the same font while this one was
+        // parsing it, in which case every template shares the first copy
+        let mut fonts = self.fonts.write().unwrap_or_else(PoisonError::into_inner);
+        let font = fonts.entry(key).or_insert_with(|| Arc::new(font));
+        Ok(Arc::clone(font))
+    }
+
+    /// Drop every face of a font from the registry so it will be read from
+    /// the disk again the next time it is requested. Templates already
+    /// holding the font keep their copy.
+    pub fn forget_font(&self, font_path: &Path) {
+        let key = FontRegistry::key(font_path);
+        self.fonts
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(font_path, _), _| *font_path != key);
+    }
+
+    /// The number of distinct fonts currently loaded.
+    pub fn len(&self) -> usize {
+        self.fonts
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Whether no fonts are currently loaded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The number of faces in a font file, which is 1 unless it is a collection.
+fn face_count(font_bytes: &[u8]) -> u32 {
+    ttf_parser::fonts_in_collection(font_bytes).unwrap_or(1)
+}
+
+/// Get the family and style names of the face at an index, preferring the
+/// typographic names that group more than four styles into one family.
+fn face_name(font_bytes: &[u8], index: u32) -> Option<(String, String)> {
+    let face = ttf_parser::Face::from_slice(font_bytes, index).ok()?;
+    let name = |name_ids: [u16; 2]| {
+        name_ids.into_iter().find_map(|name_id| {
+            face.names()
+                .into_iter()
+                .filter(|name| name.name_id == name_id)
+                .find_map(|name| name.to_string())
+        })
+    };
+    let family = name([
+        ttf_parser::name_id::TYPOGRAPHIC_FAMILY,
+        ttf_parser::name_id::FAMILY,
+    ])?;
+    let style = name([
+        ttf_parser::name_id::TYPOGRAPHIC_SUBFAMILY,
+        ttf_parser::name_id::SUBFAMILY,
+    ])
+    .unwrap_or_default();
+    Some((family, style))
+}
+
+/// Get the names of every face in a font file, for listing the faces that
+/// can be selected.
+fn face_names(font_bytes: &[u8]) -> Vec<String> {
+    (0..face_count(font_bytes))
+        .map(|index| match face_name(font_bytes, index) {
+            Some((family, style)) => format!("{index}: {family} {style}"),
+            None => format!("{index}: (unnamed)"),
+        })
+        .collect()
+}
+
+/// Find the index of a face in a font file, or None if it has no such face.
+fn find_face(font_bytes: &[u8], face: &FontFace) -> Option<u32> {
+    match face {
+        FontFace::Index(index) => (*index < face_count(font_bytes)).then_some(*index),
+        FontFace::Name { family, style } => (0..face_count(font_bytes)).find(|index| {
+            face_name(font_bytes, *index).is_some_and(|(face_family, face_style)| {
+                face_family.eq_ignore_ascii_case(family)
+                    && style
+                        .iter()
+                        .all(|style| face_style.eq_ignore_ascii_case(style))
+            })
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{fine_template, named_font_template, write_template, TEMPLATES};
+    use crate::{AutomemeError, FontFace, FontRegistry, TemplateStore};
+    use serde_json::json;
+    use std::fs::{copy, create_dir_all, read, write};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    /// Pack font files into a TrueType collection. Table offsets in each font
+    /// are counted from the start of the file, so they are moved along by
+    /// wherever the font ends up in the collection.
+    fn write_collection(collection_path: &std::path::Path, fonts: &[Vec<u8>]) {
+        let font_count = u32::try_from(fonts.len()).unwrap();
+        let mut collection = b"ttcf\x00\x01\x00\x00".to_vec();
+        collection.extend(font_count.to_be_bytes());
+        let mut offset = 12 + 4 * font_count;
+        for font in fonts {
+            collection.extend(offset.to_be_bytes());
+            offset += u32::try_from(font.len().next_multiple_of(4)).unwrap();
+        }
+        for font in fonts {
+            let font_offset = u32::try_from(collection.len()).unwrap();
+            let mut font = font.clone();
+            let table_count = usize::from(u16::from_be_bytes([font[4], font[5]]));
+            for table in 0..table_count {
+                let record = 12 + 16 * table + 8;
+                let table_offset = u32::from_be_bytes(font[record..record + 4].try_into().unwrap());
+                font[record..record + 4]
+                    .copy_from_slice(&(table_offset + font_offset).to_be_bytes());
+            }
+            font.resize(font.len().next_multiple_of(4), 0);
+            collection.extend(font);
+        }
+        write(collection_path, collection).unwrap();
+    }
+
+    #[test]
+    fn test_collection_faces() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let collection_path = root.join("Gabarito.ttc");
+        write_collection(
+            &collection_path,
+            &[
+                read(format!("{TEMPLATES}/fonts/Gabarito-Regular.ttf")).unwrap(),
+                read(format!("{TEMPLATES}/fonts/Gabarito-Bold.ttf")).unwrap(),
+            ],
+        );
+
+        let fonts = FontRegistry::new();
+        let regular = fonts.get_font(&collection_path).unwrap();
+        let bold = fonts
+            .get_font_face(&collection_path, &FontFace::Index(1))
+            .unwrap();
+        let bold_by_name = fonts
+            .get_font_face(
+                &collection_path,
+                &FontFace::Name {
+                    family: "gabarito".to_owned(),
+                    style: Some("Bold".to_owned()),
+                },
+            )
+            .unwrap();
+        let glyph = regular.lookup_glyph_index('a');
+        assert_ne!(
+            regular.metrics_indexed(glyph, 60.0).bounds,
+            bold.metrics_indexed(glyph, 60.0).bounds
+        );
+        assert_eq!(
+            bold.metrics_indexed(glyph, 60.0).bounds,
+            bold_by_name.metrics_indexed(glyph, 60.0).bounds
+        );
+
+        let Err(AutomemeError::FontFaceNotFound { available, .. }) =
+            fonts.get_font_face(&collection_path, &FontFace::Index(2))
+        else {
+            panic!("a collection of two fonts should have no face at index 2");
+        };
+        assert_eq!(available, ["0: Gabarito Regular", "1: Gabarito Bold"]);
+        assert!(fonts
+            .get_font_face(
+                &collection_path,
+                &FontFace::Name {
+                    family: "Anton".to_owned(),
+                    style: None,
+                },
+            )
+            .is_err());
+
+        // Templates select a face by index or by name
+        for (template_name, font_face) in [
+            ("by-index", json!(1)),
+            ("by-name", json!({ "family": "Gabarito", "style": "Bold" })),
+        ] {
+            let mut template = fine_template(240);
+            template["font_path"] = "Gabarito.ttc".into();
+            template["font_face"] = font_face;
+            write_template(root, template_name, &template);
+        }
+        let store = TemplateStore::new(root);
+        for template_name in ["by-index", "by-name"] {
+            let template = store.get_template_from_disk(template_name).unwrap();
+            assert_eq!(
+                template.font.metrics_indexed(glyph, 60.0).bounds,
+                bold.metrics_indexed(glyph, 60.0).bounds
+            );
+        }
+    }
+
+    #[test]
+    fn test_templates_share_fonts() {
+        let store = TemplateStore::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../templates"));
+        let pikachu = store.get_template_from_disk("pikachu").unwrap();
+        let this_is_fine = store.get_template_from_disk("this-is-fine").unwrap();
+        assert!(Arc::ptr_eq(&pikachu.font, &this_is_fine.font));
+        assert_eq!(store.fonts().len(), 1);
+    }
+
+    #[test]
+    fn test_concurrent_loads_share_one_font() {
+        let font_path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../templates/fonts/Anton-Regular.ttf"
+        );
+        let fonts = FontRegistry::new();
+        let loaded: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| fonts.get_font(font_path.as_ref()).unwrap()))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        let kept = fonts.get_font(font_path.as_ref()).unwrap();
+        assert!(loaded.iter().all(|font| Arc::ptr_eq(font, &kept)));
+        assert_eq!(fonts.len(), 1);
+    }
+
+    #[test]
+    fn test_font_by_name() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        create_dir_all(root.join("fonts")).unwrap();
+        copy(
+            format!("{TEMPLATES}/fonts/Anton-Regular.ttf"),
+            root.join("fonts/Anton-Regular.ttf"),
+        )
+        .unwrap();
+        write_template(root, "named", &named_font_template("Anton-Regular"));
+        write_template(root, "missing", &named_font_template("Comic-Sans"));
+
+        let store = TemplateStore::new(root);
+        assert!(store.get_template_from_disk("named").is_ok());
+        assert!(matches!(
+            store.get_template_from_disk("missing"),
+            Err(crate::AutomemeError::FontNotFound { .. })
+        ));
+    }
+}
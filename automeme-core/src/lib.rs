@@ -6,52 +6,217 @@
 #![allow(clippy::needless_pass_by_value)]
 #![allow(clippy::must_use_candidate)]
 
-use core::f32::consts::PI;
-use core::u8;
-use fontdue::layout::{
-    CoordinateSystem, HorizontalAlign, Layout, LayoutSettings, TextStyle, VerticalAlign, WrapStyle,
-};
-use fontdue::{Font, FontSettings};
-use glob::glob;
-use image::{Pixel, Rgba, RgbaImage};
-use serde::Deserialize;
+use core::f32::consts::SQRT_2;
+use fontdue::layout::GlyphPosition;
+use fontdue::Font;
+use image::{GrayImage, Pixel, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::{metadata, read_to_string, File};
-use std::io::Read;
+use std::fmt;
 use std::path::PathBuf;
+use std::sync::Arc;
+
+mod background;
+mod blur;
+mod error;
+mod extend;
+mod fit;
+mod fonts;
+mod markup;
+#[cfg(feature = "packs")]
+mod pack;
+mod serialize;
+mod set;
+mod source;
+mod store;
+mod stroke;
+#[cfg(test)]
+mod test_util;
+mod transform;
+mod validate;
+#[cfg(feature = "watch")]
+mod watch;
+pub use background::background_layer;
+pub use blur::blur_layer;
+pub use error::AutomemeError;
+pub use fit::{layout_text_field, FittedText, DEFAULT_MIN_TEXT_SIZE};
+pub use fonts::FontRegistry;
+pub use markup::{SpanStyle, StyledText};
+pub use set::TemplateSet;
+pub use store::TemplateStore;
+pub use stroke::stroke_mask;
+pub use transform::{rotate_layer, warp_layer};
+pub use validate::ValidationReport;
+#[cfg(feature = "watch")]
+pub use watch::TemplateWatcher;
 
 /// Sets the scale at which fonts are imported. Works best if this is close to
 /// the average size that text is rendered on images.
 const FONT_GEOMETRY_SCALE: f32 = 60.0;
 
-/// Data from the JSON template files. This struct is only used to deserialize the
-/// files before the full template struct is built.
-#[derive(Debug, Deserialize, Clone)]
+/// Data from the template files, which can be written in JSON, TOML or YAML.
+/// This struct is only used to deserialize the files before the full template
+/// struct is built, or to write templates back out with `to_json_string`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TemplateJSON {
-    /// The relative path of the base image from the project root, also used as a lookup key
+    /// The name of another template this one is based on. Anything not set
+    /// here is taken from that template, and text fields are merged one by
+    /// one so only the properties that differ need to be set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    /// Human-readable information about the template
+    #[serde(flatten)]
+    pub metadata: TemplateMetadata,
+    /// The path of the base image relative to the template root, also used as a lookup key
     pub image_path: String,
-    /// The relative path of the font from the project root, also used as a lookup key
-    pub font_path: String,
+    /// The path of the font relative to the template root, also used as a lookup key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub font_path: Option<String>,
+    /// The name of a font in the fonts directory of the template root, used if
+    /// `font_path` is not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub font: Option<String>,
+    /// Which face to use if the font is a collection of several, such as a
+    /// `.ttc` file. Uses the first face if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font_face: Option<FontFace>,
+    /// Fonts to try, in order, for characters the main font has no glyph for.
+    /// Each is a font name in the fonts directory or a path relative to the
+    /// template root.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback_fonts: Vec<String>,
     /// All places text can go in an image
     pub text_fields: Vec<TextField>,
+    /// The root paths in this template are resolved against, set when the
+    /// template file is read
+    #[serde(skip)]
+    pub(crate) root: PathBuf,
+    /// Every template file this template was read from, starting with its
+    /// own and followed by the templates it extends
+    #[serde(skip)]
+    pub(crate) files: Vec<PathBuf>,
+    /// The template's own file before it was merged with the template it
+    /// extends, set when the template file is read
+    #[serde(skip)]
+    pub(crate) file_value: Option<serde_json::Value>,
+}
+
+/// A face in a font collection, selected either by its index in the
+/// collection or by its family and style names. Fonts that are not
+/// collections only have a face at index 0.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum FontFace {
+    /// The position of the face in the collection, starting at 0
+    Index(u32),
+    /// The family name of the face, like "Noto Sans CJK JP", and optionally
+    /// its style, like "Bold". Names are matched ignoring case, and the first
+    /// face in the family is used if no style is given.
+    Name {
+        family: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        style: Option<String>,
+    },
+}
+
+impl Default for FontFace {
+    fn default() -> Self {
+        FontFace::Index(0)
+    }
+}
+
+impl fmt::Display for FontFace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FontFace::Index(index) => write!(f, "at index {index}"),
+            FontFace::Name {
+                family,
+                style: Some(style),
+            } => write!(f, "named '{family} {style}'"),
+            FontFace::Name {
+                family,
+                style: None,
+            } => write!(f, "named '{family}'"),
+        }
+    }
+}
+
+/// Optional descriptive information about a template, used for display and
+/// attribution but never for rendering.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TemplateMetadata {
+    /// Display name of the template (optional)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Short description of the template or how it is used (optional)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Keywords for searching and grouping templates
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Other names the template can be looked up by
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    /// Where the base image came from (optional)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Link to where the base image came from (optional)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+    /// License or usage note for the base image (optional)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
 }
 
 /// The full version of the template with loaded data. This can be directly
 /// passed to the render fuction to get a completed image.
 #[derive(Debug, Clone)]
 pub struct Template {
+    pub metadata: TemplateMetadata,
     pub image: RgbaImage,
-    pub font: Font,
+    pub font: Arc<Font>,
+    pub fallback_fonts: Vec<Arc<Font>>,
+    /// Fonts text fields use in place of the main font, by the name the
+    /// fields give them
+    pub field_fonts: HashMap<String, Arc<Font>>,
     pub text_fields: Vec<TextField>,
 }
 
+impl Template {
+    /// The main font followed by all fallback fonts, in the order they are
+    /// tried for each character.
+    pub fn fonts(&self) -> Vec<Arc<Font>> {
+        let mut fonts = vec![Arc::clone(&self.font)];
+        fonts.extend(self.fallback_fonts.iter().cloned());
+        fonts
+    }
+
+    /// The fonts a text field is drawn with, like `fonts` but starting with
+    /// the field's own font if it has one.
+    pub fn fonts_for(&self, text_field: &TextField) -> Vec<Arc<Font>> {
+        let mut fonts = self.fonts();
+        if let Some(font) = text_field
+            .font
+            .as_ref()
+            .and_then(|font_name| self.field_fonts.get(font_name))
+        {
+            fonts[0] = Arc::clone(font);
+        }
+        fonts
+    }
+}
+
 /// Each text field represents a location where text can be rendered. Text will
-/// be shrunk until it fits in the field specified. If the text field extends
-/// past the bounds of the image, an error will be thrown at runtime.
-#[derive(Debug, Deserialize, Clone)]
+/// be shrunk until it fits in the field specified. Fields that extend past the
+/// bounds of the image are reported by `TemplateStore::validate_template`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TextField {
     /// The default text that goes in each field
     pub text: String,
+    /// Whether the text is parsed for bold, italic and colored spans. Off by
+    /// default, so text with `*`, `~` or `\` in it is drawn as it is.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub markup: bool,
     /// Whether the text should be forced into uppercase
     pub uppercase: bool,
     /// Distance from the top-left, in [x, y] pixels, where the text field begins
@@ -60,328 +225,661 @@ pub struct TextField {
     pub end: [u32; 2],
     /// Default size of the text in this field, may be reduced to fit in bounds
     pub text_size: f32,
+    /// Smallest size the text may be reduced to, `DEFAULT_MIN_TEXT_SIZE` if
+    /// not set or set any lower. Text that still does not fit is handled by
+    /// `overflow`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_text_size: Option<f32>,
+    /// Whether words too wide for the field may be broken with hyphens when
+    /// shrinking the text would take it below the minimum size
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub hyphenate: bool,
+    /// What to do with text that does not fit even at the minimum size
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub overflow: OverflowPolicy,
+    /// Distance between the baselines of lines as a multiple of the font's
+    /// line height, 1 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_height: Option<f32>,
+    /// Extra space between characters in pixels at the full text size,
+    /// shrinking with the text. Negative spacing draws them closer together.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub letter_spacing: f32,
+    /// Font to draw this field with instead of the template's font, as a font
+    /// name in the fonts directory or a path relative to the template root
+    /// (optional). Collections use their first face.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font: Option<String>,
     /// Color of the text in RGB
     pub text_color: [u8; 3],
     /// Color of the text border in RGB (optional)
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub border_color: Option<[u8; 3]>,
+    /// Width of the text border in pixels at the full text size, shrinking
+    /// with the text. 3% of the text size by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub border_width: Option<f32>,
     /// Color of the text shadow in RGB (optional)
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub shadow_color: Option<[u8; 3]>,
+    /// How far the shadow falls from the text in pixels at the full text
+    /// size, shrinking with the text. 8.5% of the text size by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shadow_distance: Option<f32>,
+    /// Direction the shadow falls in, in degrees clockwise from the right.
+    /// 45 by default, down and to the right.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shadow_angle: Option<f32>,
+    /// How far the shadow's edges are blurred in pixels at the full text size,
+    /// shrinking with the text. Sharp by default.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub shadow_blur: f32,
+    /// Opacity of the shadow from 0 to 1, fully opaque by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shadow_opacity: Option<f32>,
+    /// Color of a box drawn behind the text in RGB (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_color: Option<[u8; 3]>,
+    /// Opacity of the background box from 0 to 1, fully opaque by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_opacity: Option<f32>,
+    /// What the background box covers, the whole field by default
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub background_fit: BackgroundFit,
+    /// How far the background box reaches past the field or the text, in
+    /// pixels
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub background_padding: f32,
+    /// Radius of the corners of the background box in pixels, square by
+    /// default
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub background_radius: f32,
+    /// Horizontal alignment of the text in the field, centered by default
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub align: TextAlign,
+    /// Vertical alignment of the text in the field, in the middle by default
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub valign: VerticalTextAlign,
+    /// Angle in degrees to turn the field clockwise about its center, for
+    /// text on tilted surfaces
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub rotation: f32,
+    /// Corners to warp the field onto in perspective, for text on surfaces
+    /// facing away from the camera, in [x, y] pixels in the order top-left,
+    /// top-right, bottom-right, bottom-left (optional). Text is still laid out
+    /// in the field between `start` and `end` before it is warped, and
+    /// `rotation` is ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub corners: Option<[[u32; 2]; 4]>,
 }
 
-/// Get a list of templates based on json filenames.
-///
-/// # Errors
-/// Will return Err if:
-/// - the pattern glob cannot be expanded
-/// - any json file in the templates directory has no filename
-/// - any filename cannot be converted to a string
-pub fn get_template_names() -> Result<Vec<String>, String> {
-    let paths = glob("templates/*.json").map_err(|e| format!("Failed to expand glob: {e}"))?;
-    let names: Result<Vec<String>, String> = paths
-        .map(|path| {
-            let file_path = path.map_err(|e| format!("Failed to read file path: {e}"))?;
-            let file_stem = file_path.file_stem().ok_or("Failed to get file stem")?;
-            let file_str = file_stem
-                .to_str()
-                .ok_or("Failed to convert OsStr to string")?;
-            Ok(file_str.to_owned())
-        })
-        .collect();
-
-    names
-}
+impl TextField {
+    /// The smallest size the text of this field may be reduced to, which is
+    /// never below `DEFAULT_MIN_TEXT_SIZE`.
+    pub fn min_text_size(&self) -> f32 {
+        self.min_text_size
+            .unwrap_or(DEFAULT_MIN_TEXT_SIZE)
+            .min(self.text_size)
+            .max(DEFAULT_MIN_TEXT_SIZE)
+    }
 
-/// Load a selected json file from the disk. Returns None if no template by
-/// that name exists.
-///
-/// # Errors
-/// Will return Err if the json string cannot be read from the file or the
-/// struct cannot be deserialized from the json string.
-fn get_json_from_disk(template_name: &String) -> Result<Option<TemplateJSON>, String> {
-    let file_path = PathBuf::from(format!("templates/{template_name}.json"));
-    if !file_path.exists() {
-        return Ok(None);
+    /// The text of this field as it is drawn, with its markup parsed if the
+    /// field uses markup and in uppercase if the field is.
+    pub fn styled_text(&self) -> StyledText {
+        let styled = if self.markup {
+            StyledText::parse(&self.text)
+        } else {
+            StyledText::plain(&self.text)
+        };
+        if self.uppercase {
+            styled.to_uppercase()
+        } else {
+            styled
+        }
     }
-    let json_string =
-        read_to_string(&file_path).map_err(|e| format!("Failed to read JSON file: {e}"))?;
-    let template_json: TemplateJSON = serde_json::from_str(&json_string)
-        .map_err(|e| format!("Failed to deserialize JSON: {e}"))?;
 
-    Ok(Some(template_json))
+    /// The corners the field is warped onto, if any, as floating point
+    /// coordinates.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn warp_corners(&self) -> Option<[[f32; 2]; 4]> {
+        self.corners
+            .map(|corners| corners.map(|corner| corner.map(|value| value as f32)))
+    }
 }
 
-/// Load a selected template and all resources from the disk. Returns None if
-/// no template by that name exists.
-///
-/// # Errors
-/// Will return Err if:
-/// - `get_json_from_disk` returns Err for the given template name
-/// - Image file cannot be opened or decoded
-/// - Font file cannot be opened, read, or loaded.
-pub fn get_template_from_disk(template_name: &String) -> Result<Option<Template>, String> {
-    if let Some(template_json) = get_json_from_disk(template_name)? {
-        // Successfully located and read the json file
-        // Open and decode the image
-        let image = image::open(&template_json.image_path)
-            .map_err(|e| format!("Failed to open file {}: {}", &template_json.image_path, e))
-            .map(|image| image.to_rgba8())
-            .map_err(|e| {
-                format!(
-                    "Failed to decode image {}: {}",
-                    &template_json.image_path, e
-                )
-            })?;
-        // Open and decode font
-        let font = File::open(&template_json.font_path)
-            .map_err(|e| format!("Failed to open file {}: {}", &template_json.font_path, e))
-            .and_then(|mut font_file| {
-                let mut font_bytes = Vec::new();
-                font_file
-                    .read_to_end(&mut font_bytes)
-                    .map_err(|e| format!("Failed to read font data: {e}"))?;
-                Font::from_bytes(
-                    font_bytes,
-                    FontSettings {
-                        collection_index: 0,
-                        scale: FONT_GEOMETRY_SCALE,
-                    },
-                )
-                .map_err(|e| format!("Failed to load font data: {e}"))
-            })?;
-        // Get text fields
-        let text_fields = template_json.text_fields;
-
-        // Return the template
-        Ok(Some(Template {
-            image,
-            font,
-            text_fields,
-        }))
-    } else {
-        // Could not find a template by that name
-        Ok(None)
-    }
+/// Where each line of text sits between the left and right edges of a field.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TextAlign {
+    Left,
+    #[default]
+    Center,
+    Right,
 }
 
-/// Load each template file in the templates directory and check that all of
-/// the referenced files (fonts, images) exist.
-///
-/// # Errors
-/// Will return Err if:
-/// - `get_template_names` returns Err
-/// - `get_template_from_disk` returns Err or None when loading a template by name
-/// - any file referenced by a template does not exist
-pub fn startup_check_all_resources() -> Result<usize, String> {
-    // Get list of template names from glob
-    let template_names = get_template_names()?;
-
-    // Load json for each template
-    let templates_json: Result<Vec<TemplateJSON>, String> = template_names
-        .iter()
-        .map(|name| match get_json_from_disk(name)? {
-            Some(template) => Ok(template),
-            None => Err(format!("Error: Template '{name}' not found.")),
-        })
-        .collect();
-
-    // Check all referenced files exist
-    for template in templates_json.clone()? {
-        for file_path in [template.image_path, template.font_path] {
-            metadata(&file_path).map_err(|e| format!("Failed to read file {file_path}: {e}"))?;
-        }
-    }
+/// Where the block of text sits between the top and bottom edges of a field.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum VerticalTextAlign {
+    Top,
+    #[default]
+    Middle,
+    Bottom,
+}
 
-    Ok(templates_json?.len())
+/// What the background box behind the text of a field covers.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BackgroundFit {
+    /// The whole field, whatever the text in it
+    #[default]
+    Field,
+    /// The lines of text, from the first character to the last
+    Text,
 }
 
-/// Load each template file in the templates directory and load everything into
-/// a single `HashMap` for faster lookup.
-///
-/// # Errors
-/// Will return Err if:
-/// - `get_template_names` returns Err
-/// - `get_template_from_disk` returns Err or None when loading a template by name
-pub fn startup_load_all_resources() -> Result<HashMap<String, Template>, String> {
-    // Get list of template names from glob
-    let template_names = get_template_names()?;
-
-    // Load all resources for each template
-    let templates: Result<HashMap<String, Template>, String> = template_names
-        .iter()
-        .map(|name| match get_template_from_disk(name)? {
-            Some(template) => Ok((name.clone(), template)),
-            None => Err(format!("Error: Template '{name}' not found.")),
-        })
-        .collect();
-
-    templates
+/// What to do with text that does not fit in its field at the minimum size.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// Cut the text off and end it with an ellipsis
+    #[default]
+    Ellipsis,
+    /// Draw all of the text, spilling past the edges of the field
+    Overflow,
+    /// Fail to render the template
+    Error,
 }
 
+/// Whether a value is its default, so template files can leave it out.
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Width bold text is grown by on each side, relative to the text size.
+const BOLD_WEIGHT: f32 = 0.02;
+
+/// How far italic text leans right for each pixel above the baseline.
+const ITALIC_SLANT: f32 = 0.2;
+
 /// Create a transparent image layer with the rendered text to be overlayed on
-/// the final image. To generate a border layer, increase the `blot_radius` to
-/// something greater than 0.
-#[allow(clippy::cast_sign_loss)]
-#[allow(clippy::cast_precision_loss)]
+/// the final image. The fonts must be the same ones the text was laid out with.
+/// To generate a border layer, set `stroke_width` to the width of the border
+/// in pixels. With `span_colors`, spans of text with their own color are drawn
+/// in it rather than in `text_color`, as for the text itself but not its
+/// border or shadow.
 #[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
 pub fn generate_text_layer(
-    layout: &Layout,
-    font: &Font,
+    text: &FittedText,
+    fonts: &[Arc<Font>],
     text_color: [u8; 3],
-    blot_radius: f32,
+    stroke_width: f32,
+    span_colors: bool,
 ) -> RgbaImage {
     // Generate mask canvas
-    let mut text_canvas = RgbaImage::new(
-        layout.settings().max_width.unwrap_or_default() as u32,
-        layout.settings().max_height.unwrap_or_default() as u32,
-    );
+    let (width, height) = text.canvas_size;
 
-    // Generate blot pattern
-    let mut blot_pattern = Vec::new();
-    for theta in 0..360 {
-        let theta_rad = theta as f32 * PI / 180.0;
-        let point = (
-            (blot_radius * theta_rad.cos()) as i32,
-            (blot_radius * theta_rad.sin()) as i32,
-        );
-        if !blot_pattern.contains(&point) {
-            blot_pattern.push(point);
+    // Draw each style on its own, since bold text is grown after drawing
+    let mut styles: Vec<SpanStyle> = Vec::new();
+    for glyph in &text.glyphs {
+        if !styles.contains(&glyph.user_data) {
+            styles.push(glyph.user_data);
         }
     }
+    let draw_style = |style: SpanStyle| {
+        let mut style_mask = GrayImage::new(width, height);
+        let mut text_size: f32 = 0.0;
+        for glyph in text.glyphs.iter().filter(|g| g.user_data == style) {
+            text_size = text_size.max(glyph.key.px);
+            let slant = if style.italic { ITALIC_SLANT } else { 0.0 };
+            draw_glyph(&mut style_mask, glyph, fonts, slant);
+        }
+        if style.bold {
+            style_mask = stroke_mask(&style_mask, text_size * BOLD_WEIGHT);
+        }
+        style_mask
+    };
+    let style_color = |style: SpanStyle| match style.color {
+        Some(color) if span_colors => color,
+        _ => text_color,
+    };
 
-    // Generate glyph pattern from the layout
-    for glyph in layout.glyphs() {
-        // Generate pixel layout for each glyph
-        let (metrics, bytes) = font.rasterize_config(glyph.key);
-        let glyph_start = (glyph.x as u32, glyph.y as u32);
-
-        // Print pixels to the canvas
-        for x in 0..metrics.width {
-            for y in 0..metrics.height {
-                // Get coverage data from rasterization
-                let byte_index = y * metrics.width + x;
-                if let Some(mask) = bytes.get(byte_index) {
-                    // Blot pixels around the rendered pixel
-                    for blot_pattern_point in &blot_pattern {
-                        let blot_point = (
-                            (glyph_start.0 + x as u32).saturating_add_signed(blot_pattern_point.0),
-                            (glyph_start.1 + y as u32).saturating_add_signed(blot_pattern_point.1),
-                        );
-                        if let Some(p) =
-                            text_canvas.get_pixel_mut_checked(blot_point.0, blot_point.1)
-                        {
-                            p.blend(&Rgba([text_color[0], text_color[1], text_color[2], *mask]));
-                        }
+    // Text in a single style is all in one color, otherwise each style is laid
+    // over the ones before it, blending the colors where they overlap
+    let mut mask = GrayImage::new(width, height);
+    let mut single_color = text_color;
+    let mut colors: Vec<[f32; 3]> = Vec::new();
+    if let [style] = styles[..] {
+        mask = draw_style(style);
+        single_color = style_color(style);
+    } else if !styles.is_empty() {
+        colors = vec![text_color.map(f32::from); (width * height) as usize];
+        for style in styles {
+            let above_color = style_color(style).map(f32::from);
+            for ((covered, coverage), color) in mask
+                .pixels_mut()
+                .zip(draw_style(style).pixels())
+                .zip(colors.iter_mut())
+            {
+                let (below, above) = (
+                    f32::from(covered.0[0]) / 255.0,
+                    f32::from(coverage.0[0]) / 255.0,
+                );
+                let total = above + below * (1.0 - above);
+                if total > 0.0 {
+                    for (channel, above_channel) in color.iter_mut().zip(above_color) {
+                        *channel =
+                            (above_channel * above + *channel * below * (1.0 - above)) / total;
                     }
                 }
+                covered.0[0] = (total * 255.0).round() as u8;
             }
         }
     }
 
-    text_canvas
+    // Grow the border out of the text and color it in
+    let mask = stroke_mask(&mask, stroke_width);
+    let mut layer = RgbaImage::new(width, height);
+    for (index, (pixel, coverage)) in layer.pixels_mut().zip(mask.pixels()).enumerate() {
+        let [red, green, blue] = colors.get(index).map_or(single_color, |color| {
+            color.map(|channel| channel.round() as u8)
+        });
+        *pixel = Rgba([red, green, blue, coverage.0[0]]);
+    }
+    layer
+}
+
+/// Draw the coverage of a glyph onto a mask, where overlapping glyphs cover
+/// more. Rows of the glyph lean right by `slant` for each pixel they are above
+/// the baseline, spread over the two pixels they land between.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_possible_wrap)]
+#[allow(clippy::cast_precision_loss)]
+fn draw_glyph(
+    mask: &mut GrayImage,
+    glyph: &GlyphPosition<SpanStyle>,
+    fonts: &[Arc<Font>],
+    slant: f32,
+) {
+    let (metrics, bytes) = fonts[glyph.font_index].rasterize_config(glyph.key);
+    let baseline = metrics.height as f32 + metrics.ymin as f32;
+    for (y, row) in bytes.chunks_exact(metrics.width.max(1)).enumerate() {
+        let Ok(mask_y) = u32::try_from(glyph.y as i64 + y as i64) else {
+            continue;
+        };
+        let shift = slant * (baseline - y as f32 - 0.5);
+        let row_start = glyph.x as i64 + shift.floor() as i64;
+        let fraction = shift - shift.floor();
+        for (x, &coverage) in row.iter().enumerate() {
+            if coverage == 0 {
+                continue;
+            }
+            let mask_x = row_start + x as i64;
+            if fraction == 0.0 {
+                cover(mask, mask_x, mask_y, f32::from(coverage));
+            } else {
+                cover(mask, mask_x, mask_y, f32::from(coverage) * (1.0 - fraction));
+                cover(mask, mask_x + 1, mask_y, f32::from(coverage) * fraction);
+            }
+        }
+    }
+}
+
+/// Add coverage to a pixel of a mask, if it is in the mask.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+fn cover(mask: &mut GrayImage, x: i64, y: u32, coverage: f32) {
+    let Ok(x) = u32::try_from(x) else { return };
+    if let Some(p) = mask.get_pixel_mut_checked(x, y) {
+        let covered = f32::from(p.0[0]);
+        p.0[0] = (covered + coverage * (255.0 - covered) / 255.0).round() as u8;
+    }
 }
 
 /// Overlay a text layer with transparency onto the base image. To simulate a
 /// drop shadow, increase the starting position to move the canvas down/right.
-pub fn blend_layer_onto_image(image: &mut RgbaImage, layer: &RgbaImage, start: (u32, u32)) {
+/// The start can be negative for layers hanging off the top or left edge,
+/// such as rotated layers.
+pub fn blend_layer_onto_image(image: &mut RgbaImage, layer: &RgbaImage, start: (i32, i32)) {
     for (x, y, overlay_pixel) in layer.enumerate_pixels() {
         if overlay_pixel.0[3] != 0 {
-            if let Some(p) = image.get_pixel_mut_checked(start.0 + x, start.1 + y) {
-                p.blend(overlay_pixel);
-            };
+            let x = u32::try_from(i64::from(start.0) + i64::from(x));
+            let y = u32::try_from(i64::from(start.1) + i64::from(y));
+            if let (Ok(x), Ok(y)) = (x, y) {
+                if let Some(p) = image.get_pixel_mut_checked(x, y) {
+                    p.blend(overlay_pixel);
+                }
+            }
+        }
+    }
+}
+
+/// Splits text into runs that can each be drawn with a single font. Each
+/// character uses the first font with a glyph for it, or the main font if none
+/// have one. Whitespace stays with the run before it to avoid needless splits.
+/// Returns each run with the index of its font.
+pub fn split_text_by_font<'a>(text: &'a str, fonts: &[Arc<Font>]) -> Vec<(&'a str, usize)> {
+    let mut runs = Vec::new();
+    let mut run_start = 0;
+    let mut run_font = None;
+    for (index, character) in text.char_indices() {
+        if character.is_whitespace() {
+            continue;
+        }
+        let font_index = fonts
+            .iter()
+            .position(|font| font.lookup_glyph_index(character) != 0)
+            .unwrap_or(0);
+        match run_font {
+            Some(current) if current != font_index => {
+                runs.push((&text[run_start..index], current));
+                run_start = index;
+                run_font = Some(font_index);
+            }
+            Some(_) => {}
+            None => run_font = Some(font_index),
         }
     }
+    if run_start < text.len() {
+        runs.push((&text[run_start..], run_font.unwrap_or(0)));
+    }
+    runs
+}
+
+/// Create a transparent image layer with the background box of a field, if it
+/// has one, to go beneath its text. The layer reaches `margin` pixels past the
+/// text layers on each side to hold the padding around the box, and the
+/// margin is returned with it. `field_start` is where the field starts in the
+/// text layers, which are larger than the field when text overflows it.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn generate_background_layer(
+    text_field: &TextField,
+    text: &FittedText,
+    field_start: (f32, f32),
+) -> Option<(RgbaImage, u32)> {
+    let color = text_field.background_color?;
+    let (width, height) = text.canvas_size;
+    let field_width = text_field.end[0].saturating_sub(text_field.start[0]) as f32;
+    let field_height = text_field.end[1].saturating_sub(text_field.start[1]) as f32;
+    let (field_left, field_top) = field_start;
+    let [left, top, right, bottom] = match text_field.background_fit {
+        BackgroundFit::Field => [
+            field_left,
+            field_top,
+            field_left + field_width,
+            field_top + field_height,
+        ],
+        BackgroundFit::Text => text.text_bounds()?,
+    };
+    let padding = text_field.background_padding.max(0.0);
+    let margin = padding.ceil() as u32;
+    let reach = margin as f32 - padding;
+    let layer = background_layer(
+        (width + 2 * margin, height + 2 * margin),
+        [
+            left + reach,
+            top + reach,
+            right + 2.0 * padding + reach,
+            bottom + 2.0 * padding + reach,
+        ],
+        text_field.background_radius,
+        color,
+        text_field.background_opacity.unwrap_or(1.0),
+    );
+    Some((layer, margin))
+}
+
+/// Warp or rotate a layer of a text field and find where it goes on the
+/// image, given where the field's text layers start. The layer can reach
+/// `margin` pixels past the text layers on each side, and stays centered on
+/// them.
+fn place_layer_with_margin(
+    layer: RgbaImage,
+    text_field: &TextField,
+    start: (i32, i32),
+    margin: u32,
+) -> (RgbaImage, (i32, i32)) {
+    if let Some(corners) = text_field.warp_corners() {
+        let corners = if margin == 0 {
+            corners
+        } else {
+            let size = (layer.width() - 2 * margin, layer.height() - 2 * margin);
+            transform::grow_quad(corners, size, margin)
+        };
+        return warp_layer(&layer, corners);
+    }
+    let margin = i32::try_from(margin).unwrap_or(i32::MAX);
+    let start = (
+        start.0.saturating_sub(margin),
+        start.1.saturating_sub(margin),
+    );
+    if text_field.rotation == 0.0 {
+        return (layer, start);
+    }
+    let (layer, offset) = rotate_layer(&layer, text_field.rotation);
+    (
+        layer,
+        (
+            start.0.saturating_add(offset.0),
+            start.1.saturating_add(offset.1),
+        ),
+    )
 }
 
 /// Given one text field, generates all text effects and layers them onto the
 /// base image.
-//#[allow(clippy::cast_sign_loss)]
-#[allow(clippy::cast_precision_loss)]
+///
+/// # Errors
+/// Will return Err if the text does not fit in the field and the field's
+/// overflow policy is to fail.
 #[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
 fn render_text_field_on_image(
     mut image: RgbaImage,
+    index: usize,
     text_field: &TextField,
-    font: &Font,
-) -> RgbaImage {
-    // Get field width & height
-    let field_width = (text_field.end[0] - text_field.start[0]) as f32;
-    let field_height = (text_field.end[1] - text_field.start[1]) as f32;
-
-    // Generate a text field layout object
-    let mut layout = Layout::new(CoordinateSystem::PositiveYDown);
-    layout.reset(&LayoutSettings {
-        x: 0.0,
-        y: 0.0,
-        max_height: Some(field_height),
-        max_width: Some(field_width),
-        horizontal_align: HorizontalAlign::Center,
-        vertical_align: VerticalAlign::Middle,
-        wrap_style: WrapStyle::Word,
-        ..Default::default()
-    });
-
-    // Optionally convert to uppercase
-    let text = if text_field.uppercase {
-        text_field.text.to_uppercase()
-    } else {
-        text_field.text.clone()
-    };
-
-    // Add text to layout
-    let mut text_size = text_field.text_size;
-    layout.append(&[font], &TextStyle::new(&text, text_size, 0));
+    fonts: &[Arc<Font>],
+) -> Result<RgbaImage, AutomemeError> {
+    // Lay out and fit the text
+    let fitted =
+        layout_text_field(text_field, fonts).ok_or_else(|| AutomemeError::TextOverflow {
+            field: index,
+            min_text_size: text_field.min_text_size(),
+        })?;
+    let text_size = fitted.text_size;
+
+    // Text that overflows the field spills past the edges it is aligned away
+    // from
+    let field_width = text_field.end[0].saturating_sub(text_field.start[0]) as f32;
+    let field_height = text_field.end[1].saturating_sub(text_field.start[1]) as f32;
+    let overflow = (
+        fitted.canvas_size.0 as f32 - field_width,
+        fitted.canvas_size.1 as f32 - field_height,
+    );
+    let overflow_offset = (
+        match text_field.align {
+            TextAlign::Left => 0.0,
+            TextAlign::Center => (overflow.0 / 2.0).floor(),
+            TextAlign::Right => overflow.0,
+        },
+        match text_field.valign {
+            VerticalTextAlign::Top => 0.0,
+            VerticalTextAlign::Middle => (overflow.1 / 2.0).trunc(),
+            VerticalTextAlign::Bottom => overflow.1,
+        },
+    );
 
-    // Shrink text to fit the field if necessary
-    while layout.height() > field_height {
-        text_size -= 1.0;
-        layout.clear();
-        layout.append(&[font], &TextStyle::new(&text, text_size, 0));
+    // Warp or rotate each layer and find where it goes
+    let start = (
+        i32::try_from(text_field.start[0])
+            .unwrap_or(i32::MAX)
+            .saturating_sub(overflow_offset.0 as i32),
+        i32::try_from(text_field.start[1])
+            .unwrap_or(i32::MAX)
+            .saturating_sub(overflow_offset.1 as i32),
+    );
+    let place_layer = |layer: RgbaImage| place_layer_with_margin(layer, text_field, start, 0);
+
+    // Generate & add background layer, beneath every other layer
+    if let Some((background_canvas, margin)) =
+        generate_background_layer(text_field, &fitted, overflow_offset)
+    {
+        let (background_canvas, background_start) =
+            place_layer_with_margin(background_canvas, text_field, start, margin);
+        blend_layer_onto_image(&mut image, &background_canvas, background_start);
     }
 
     // Generate text layer
-    let text_canvas = generate_text_layer(&layout, font, text_field.text_color, 0.0);
+    let text_canvas = generate_text_layer(&fitted, fonts, text_field.text_color, 0.0, true);
 
-    // Generate & add shadow layer
+    // Sizes in the template are for text at its full size
+    let scale = |size: f32| size * text_size / text_field.text_size;
+
+    // Generate & add shadow layer, blurred and offset after rotating so the
+    // light always comes from the same direction
     if let Some(shadow_color) = text_field.shadow_color {
-        let shadow_offset = (text_size * 0.06) as i32;
-        let shadow_canvas = generate_text_layer(&layout, font, shadow_color, 0.0);
+        let (mut shadow_canvas, shadow_start) = place_layer(generate_text_layer(
+            &fitted,
+            fonts,
+            shadow_color,
+            0.0,
+            false,
+        ));
+        let opacity = text_field.shadow_opacity.unwrap_or(1.0).clamp(0.0, 1.0);
+        for pixel in shadow_canvas.pixels_mut() {
+            pixel.0[3] = (f32::from(pixel.0[3]) * opacity).round() as u8;
+        }
+        let (shadow_canvas, blur_offset) =
+            blur_layer(&shadow_canvas, scale(text_field.shadow_blur));
+        let offset = match (text_field.shadow_distance, text_field.shadow_angle) {
+            // The default offset is truncated the same way as before shadows
+            // could be moved, so existing templates keep rendering the same
+            (None, None) => {
+                let offset = (text_size * 0.06) as i32;
+                (offset, offset)
+            }
+            (distance, angle) => {
+                let distance = distance.map_or(text_size * 0.06 * SQRT_2, scale);
+                let (sin, cos) = angle.unwrap_or(45.0).to_radians().sin_cos();
+                ((distance * cos) as i32, (distance * sin) as i32)
+            }
+        };
         blend_layer_onto_image(
             &mut image,
             &shadow_canvas,
             (
-                text_field.start[0].saturating_add_signed(shadow_offset),
-                text_field.start[1].saturating_add_signed(shadow_offset),
+                shadow_start.0 + blur_offset.0 + offset.0,
+                shadow_start.1 + blur_offset.1 + offset.1,
             ),
         );
-    };
+    }
 
     // Generate & add border layer
     if let Some(border_color) = text_field.border_color {
-        let border_size = text_size * 0.03;
-        let border_canvas = generate_text_layer(&layout, font, border_color, border_size);
-        blend_layer_onto_image(
-            &mut image,
-            &border_canvas,
-            (text_field.start[0], text_field.start[1]),
-        );
-    };
+        let border_size = text_field.border_width.map_or(text_size * 0.03, scale);
+        let (border_canvas, border_start) = place_layer(generate_text_layer(
+            &fitted,
+            fonts,
+            border_color,
+            border_size,
+            false,
+        ));
+        blend_layer_onto_image(&mut image, &border_canvas, border_start);
+    }
 
     // Add text layer
-    blend_layer_onto_image(
-        &mut image,
-        &text_canvas,
-        (text_field.start[0], text_field.start[1]),
-    );
+    let (text_canvas, text_start) = place_layer(text_canvas);
+    blend_layer_onto_image(&mut image, &text_canvas, text_start);
 
-    image
+    Ok(image)
 }
 
 /// Renders a template completely. If a template is passed directly after
 /// loading, all default values will be used. Edit the template before passing
 /// here to e.g. edit the text in each field.
-pub fn render_template(template: Template) -> RgbaImage {
-    let mut image = template.image;
-    for text_field in template.text_fields {
-        image = render_text_field_on_image(image, &text_field, &template.font);
+///
+/// # Errors
+/// Will return Err if the text of a field does not fit and the field's
+/// overflow policy is to fail.
+pub fn render_template(mut template: Template) -> Result<RgbaImage, AutomemeError> {
+    let image = std::mem::take(&mut template.image);
+    render_text_fields(image, &template, &template.text_fields)
+}
+
+/// Renders a template with other text fields in place of its own, such as
+/// fields with text given by a user. The template is left as it is, and only
+/// its image is copied to draw on.
+///
+/// # Errors
+/// Will return Err if the text of a field does not fit and the field's
+/// overflow policy is to fail.
+pub fn render_template_with_fields(
+    template: &Template,
+    text_fields: &[TextField],
+) -> Result<RgbaImage, AutomemeError> {
+    render_text_fields(template.image.clone(), template, text_fields)
+}
+
+/// Draws text fields onto an image with the fonts of a template.
+///
+/// # Errors
+/// Will return Err if the text of a field does not fit and the field's
+/// overflow policy is to fail.
+fn render_text_fields(
+    mut image: RgbaImage,
+    template: &Template,
+    text_fields: &[TextField],
+) -> Result<RgbaImage, AutomemeError> {
+    for (index, text_field) in text_fields.iter().enumerate() {
+        let fonts = template.fonts_for(text_field);
+        image = render_text_field_on_image(image, index, text_field, &fonts)?;
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{layout_text_field, TemplateStore, TextAlign, VerticalTextAlign};
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn test_field_alignment() {
+        let store = TemplateStore::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../templates"));
+        let template = store.get_template_from_disk("pikachu").unwrap();
+        let fonts = template.fonts();
+        let mut text_field = template.text_fields[0].clone();
+        text_field.text = "short".to_owned();
+        let field_width = (text_field.end[0] - text_field.start[0]) as f32;
+        let field_height = (text_field.end[1] - text_field.start[1]) as f32;
+
+        // Get the left, top, right and bottom edges of the laid out text
+        let bounds = |align, valign| {
+            let mut text_field = text_field.clone();
+            text_field.align = align;
+            text_field.valign = valign;
+            let layout = layout_text_field(&text_field, &fonts).unwrap().layout;
+            layout.glyphs().iter().fold(
+                [f32::MAX, f32::MAX, f32::MIN, f32::MIN],
+                |[left, top, right, bottom], glyph| {
+                    [
+                        left.min(glyph.x),
+                        top.min(glyph.y),
+                        right.max(glyph.x + glyph.width as f32),
+                        bottom.max(glyph.y + glyph.height as f32),
+                    ]
+                },
+            )
+        };
+
+        let [left, top, ..] = bounds(TextAlign::Left, VerticalTextAlign::Top);
+        assert!(left < 10.0 && top < field_height / 4.0);
+        let [_, _, right, bottom] = bounds(TextAlign::Right, VerticalTextAlign::Bottom);
+        assert!(right > field_width - 10.0 && bottom > field_height * 3.0 / 4.0);
+        let [left, _, right, _] = bounds(TextAlign::Center, VerticalTextAlign::Middle);
+        assert!((left - (field_width - right)).abs() < 10.0);
     }
-    image
 }
@@ -0,0 +1,306 @@
+//! Inline markup in field text for bold, italic and colored spans.
+//!
+//! `*bold*`, `~italic~` and `{red:colored}` spans can be nested, and colors
+//! are either names or hex codes like `{#ff8800:orange}`. A backslash before
+//! `*`, `~`, `{`, `}` or another backslash draws that character as it is, and
+//! any other backslash or a marker without a match is drawn as it is too.
+//! Markup is only parsed in fields that turn it on.
+
+/// Style of a span of text, carried by each glyph in a layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SpanStyle {
+    /// Whether the text is drawn heavier than the font
+    pub bold: bool,
+    /// Whether the text is slanted
+    pub italic: bool,
+    /// Color of the text in RGB, or the field's text color if None
+    pub color: Option<[u8; 3]>,
+}
+
+/// Text with its markup taken out, and the style of each character.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StyledText {
+    text: String,
+    styles: Vec<SpanStyle>,
+}
+
+impl StyledText {
+    /// Parse the markup in some text.
+    pub fn parse(markup: &str) -> Self {
+        let chars: Vec<char> = markup.chars().collect();
+        let mut styled = StyledText::default();
+        styled.parse_span(&chars, SpanStyle::default());
+        styled
+    }
+
+    /// Text without any markup, all in the default style.
+    pub fn plain(text: &str) -> Self {
+        StyledText {
+            text: text.to_owned(),
+            styles: vec![SpanStyle::default(); text.chars().count()],
+        }
+    }
+
+    /// The text without markup.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The same text in uppercase, keeping the style of each character.
+    #[must_use]
+    pub fn to_uppercase(&self) -> Self {
+        let mut uppercase = StyledText::default();
+        for (c, style) in self.text.chars().zip(&self.styles) {
+            for upper in c.to_uppercase() {
+                uppercase.push(upper, *style);
+            }
+        }
+        uppercase
+    }
+
+    /// Each character of the text with its style.
+    pub(crate) fn chars(&self) -> impl Iterator<Item = (char, SpanStyle)> + '_ {
+        self.text.chars().zip(self.styles.iter().copied())
+    }
+
+    /// Split the text into runs of a single style.
+    pub(crate) fn runs(&self) -> Vec<(&str, SpanStyle)> {
+        let mut runs = Vec::new();
+        let mut run_start = 0;
+        let mut run_style = self.styles.first().copied().unwrap_or_default();
+        for ((index, _), style) in self.text.char_indices().zip(&self.styles) {
+            if *style != run_style {
+                runs.push((&self.text[run_start..index], run_style));
+                run_start = index;
+                run_style = *style;
+            }
+        }
+        if run_start < self.text.len() {
+            runs.push((&self.text[run_start..], run_style));
+        }
+        runs
+    }
+
+    /// Split the text into pieces that each end with a character matching a
+    /// pattern, except maybe the last, like `str::split_inclusive`.
+    pub(crate) fn split_inclusive(&self, pattern: impl Fn(char) -> bool) -> Vec<StyledText> {
+        let mut pieces = Vec::new();
+        let mut piece = StyledText::default();
+        for (c, style) in self.chars() {
+            piece.push(c, style);
+            if pattern(c) {
+                pieces.push(std::mem::take(&mut piece));
+            }
+        }
+        if !piece.text.is_empty() {
+            pieces.push(piece);
+        }
+        pieces
+    }
+
+    /// Add a character in a style.
+    pub(crate) fn push(&mut self, c: char, style: SpanStyle) {
+        self.text.push(c);
+        self.styles.push(style);
+    }
+
+    /// Add the characters of other text, keeping their styles.
+    pub(crate) fn push_styled(&mut self, other: &StyledText) {
+        self.text.push_str(&other.text);
+        self.styles.extend_from_slice(&other.styles);
+    }
+
+    /// Add characters that were not in the text to begin with, as when
+    /// breaking lines or cutting the text off, in the style of the character
+    /// before them.
+    pub(crate) fn push_added(&mut self, added: &str) {
+        let style = self.styles.last().copied().unwrap_or_default();
+        for c in added.chars() {
+            self.push(c, style);
+        }
+    }
+
+    /// Add the characters of a span, parsing any spans inside it.
+    fn parse_span(&mut self, chars: &[char], style: SpanStyle) {
+        let mut index = 0;
+        while index < chars.len() {
+            match chars[index] {
+                '\\' if escapes(chars, index) => {
+                    self.push(chars[index + 1], style);
+                    index += 2;
+                }
+                marker @ ('*' | '~') => match find_unescaped(chars, index + 1, marker) {
+                    Some(end) if end > index + 1 => {
+                        let inner = SpanStyle {
+                            bold: style.bold || marker == '*',
+                            italic: style.italic || marker == '~',
+                            ..style
+                        };
+                        self.parse_span(&chars[index + 1..end], inner);
+                        index = end + 1;
+                    }
+                    _ => {
+                        self.push(marker, style);
+                        index += 1;
+                    }
+                },
+                '{' => {
+                    if let Some((color, start, end)) = color_span(chars, index) {
+                        let inner = SpanStyle {
+                            color: Some(color),
+                            ..style
+                        };
+                        self.parse_span(&chars[start..end], inner);
+                        index = end + 1;
+                    } else {
+                        self.push('{', style);
+                        index += 1;
+                    }
+                }
+                c => {
+                    self.push(c, style);
+                    index += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Whether the character at a position is a backslash escaping the one after
+/// it, which only markup characters and backslashes can be.
+fn escapes(chars: &[char], index: usize) -> bool {
+    chars[index] == '\\' && matches!(chars.get(index + 1), Some('*' | '~' | '{' | '}' | '\\'))
+}
+
+/// Find the next marker from a position that isn't escaped with a backslash.
+fn find_unescaped(chars: &[char], from: usize, marker: char) -> Option<usize> {
+    let mut index = from;
+    while index < chars.len() {
+        match chars[index] {
+            '\\' if escapes(chars, index) => index += 2,
+            c if c == marker => return Some(index),
+            _ => index += 1,
+        }
+    }
+    None
+}
+
+/// Read a colored span starting at an opening brace. Returns its color and
+/// where its text starts and ends, or None if it isn't a colored span.
+fn color_span(chars: &[char], open: usize) -> Option<([u8; 3], usize, usize)> {
+    let name_end = open + chars[open..].iter().position(|c| *c == ':')?;
+    let name: String = chars[open + 1..name_end].iter().collect();
+    let color = parse_color(&name)?;
+
+    // Braces inside the span must be matched too
+    let mut depth = 0;
+    let mut index = name_end + 1;
+    while index < chars.len() {
+        match chars[index] {
+            '\\' if escapes(chars, index) => index += 1,
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some((color, name_end + 1, index)),
+            '}' => depth -= 1,
+            _ => {}
+        }
+        index += 1;
+    }
+    None
+}
+
+/// Read a color name or a hex code with 3 or 6 digits.
+fn parse_color(name: &str) -> Option<[u8; 3]> {
+    if let Some(hex) = name.strip_prefix('#') {
+        let digits: Vec<u8> = hex
+            .chars()
+            .map(|c| c.to_digit(16).and_then(|digit| u8::try_from(digit).ok()))
+            .collect::<Option<_>>()?;
+        return match digits[..] {
+            [r, g, b] => Some([r * 17, g * 17, b * 17]),
+            [r1, r2, g1, g2, b1, b2] => Some([r1 * 16 + r2, g1 * 16 + g2, b1 * 16 + b2]),
+            _ => None,
+        };
+    }
+    Some(match name.to_lowercase().as_str() {
+        "red" => [230, 30, 30],
+        "orange" => [255, 140, 0],
+        "yellow" => [255, 220, 0],
+        "green" => [40, 180, 40],
+        "blue" => [30, 90, 230],
+        "purple" => [140, 50, 200],
+        "pink" => [255, 105, 180],
+        "white" => [255, 255, 255],
+        "black" => [0, 0, 0],
+        "gray" | "grey" => [128, 128, 128],
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SpanStyle, StyledText};
+
+    const BOLD: SpanStyle = SpanStyle {
+        bold: true,
+        italic: false,
+        color: None,
+    };
+    const RED_ITALIC: SpanStyle = SpanStyle {
+        bold: false,
+        italic: true,
+        color: Some([230, 30, 30]),
+    };
+
+    #[test]
+    fn test_parse_markup() {
+        let styled = StyledText::parse("a *bold* and {red:~red~} word");
+        assert_eq!(styled.text(), "a bold and red word");
+        assert_eq!(
+            styled.runs(),
+            [
+                ("a ", SpanStyle::default()),
+                ("bold", BOLD),
+                (" and ", SpanStyle::default()),
+                ("red", RED_ITALIC),
+                (" word", SpanStyle::default()),
+            ]
+        );
+
+        // Added characters take the style before them
+        let mut added = StyledText::parse("a *bo*");
+        added.push_added("-\n");
+        added.push_styled(&StyledText::parse("*ld*"));
+        assert_eq!(
+            added.to_uppercase().runs(),
+            [("A ", SpanStyle::default()), ("BO-\nLD", BOLD)]
+        );
+
+        // Escaped and unmatched markers stay as they are
+        for text in [
+            "2 * 3 = 6",
+            "\\*not bold\\*",
+            "{nocolor:text}",
+            "{red:open",
+            "**",
+        ] {
+            let styled = StyledText::parse(text);
+            assert_eq!(styled.runs().len(), 1, "{text}");
+        }
+        assert_eq!(StyledText::parse("\\*not bold\\*").text(), "*not bold*");
+        assert_eq!(StyledText::parse("\\\\*bold*").text(), "\\bold");
+
+        let hex = StyledText::parse("{#f80:{#ff8800:same}}");
+        assert_eq!(hex.runs()[0].1.color, Some([255, 136, 0]));
+        // Backslashes before anything else are kept
+        assert_eq!(StyledText::parse("C:\\Users\\me").text(), "C:\\Users\\me");
+    }
+
+    #[test]
+    fn test_plain_text_is_unchanged() {
+        for text in ["C:\\Users\\me", "2*3*4", "~approx~", "{red:not red}", "\\*"] {
+            let plain = StyledText::plain(text);
+            assert_eq!(plain.text(), text);
+            assert_eq!(plain.runs(), [(text, SpanStyle::default())]);
+        }
+    }
+}
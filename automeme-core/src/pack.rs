@@ -0,0 +1,337 @@
+//! Reading template packs: zip or tar archives laid out like a template
+//! directory, with every path inside the templates relative to the archive
+//! root.
+
+use crate::source::TemplateSource;
+use crate::{AutomemeError, TemplateStore};
+use flate2::read::GzDecoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+impl TemplateStore {
+    /// Create a store that reads templates from a template pack. Packs can be
+    /// `.zip`, `.tar`, `.tar.gz` or `.tgz` archives. Paths to files in the
+    /// pack start with the path of the pack itself.
+    ///
+    /// # Errors
+    /// Will return Err if the pack cannot be read or is not a supported
+    /// archive.
+    pub fn from_pack(pack_path: impl AsRef<Path>) -> Result<Self, AutomemeError> {
+        Ok(TemplateStore::from_source(read_pack(pack_path.as_ref())?))
+    }
+
+    /// Add a template pack to the store. Templates in the pack are only used
+    /// if no source already in the store has a template by the same name;
+    /// see `get_name_collisions` for the names that are hidden.
+    ///
+    /// # Errors
+    /// Will return Err if the pack cannot be read or is not a supported
+    /// archive.
+    pub fn with_pack(self, pack_path: impl AsRef<Path>) -> Result<Self, AutomemeError> {
+        Ok(self.with_source(read_pack(pack_path.as_ref())?))
+    }
+}
+
+/// Read every file in a pack into memory.
+///
+/// # Errors
+/// Will return Err if the pack cannot be read or is not a supported archive.
+fn read_pack(pack_path: &Path) -> Result<TemplateSource, AutomemeError> {
+    let io_error = |source: io::Error| AutomemeError::Io {
+        path: pack_path.to_path_buf(),
+        source,
+    };
+    let file = File::open(pack_path).map_err(io_error)?;
+    let file_name = pack_path
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .ok_or_else(|| AutomemeError::InvalidPath(pack_path.to_path_buf()))?
+        .to_ascii_lowercase();
+    let has_extension = |extension: &str| {
+        file_name
+            .strip_suffix(extension)
+            .is_some_and(|stem| stem.ends_with('.'))
+    };
+
+    let files = if has_extension("zip") {
+        read_zip(file).map_err(|e| AutomemeError::Archive {
+            path: pack_path.to_path_buf(),
+            message: e.to_string(),
+        })?
+    } else if has_extension("tar") {
+        read_tar(file).map_err(io_error)?
+    } else if has_extension("tar.gz") || has_extension("tgz") {
+        read_tar(GzDecoder::new(file)).map_err(io_error)?
+    } else {
+        return Err(AutomemeError::Archive {
+            path: pack_path.to_path_buf(),
+            message: "expected a .zip, .tar, .tar.gz or .tgz file".to_owned(),
+        });
+    };
+
+    Ok(TemplateSource::Archive {
+        root: pack_path.to_path_buf(),
+        files: Arc::new(files),
+    })
+}
+
+/// Read every file in a zip archive.
+///
+/// # Errors
+/// Will return Err if the archive or any file in it cannot be read.
+fn read_zip(reader: impl Read + Seek) -> zip::result::ZipResult<HashMap<PathBuf, Vec<u8>>> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+    let mut files = HashMap::new();
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        if file.is_dir() {
+            continue;
+        }
+        let Some(file_path) = file.enclosed_name().and_then(clean_path) else {
+            continue;
+        };
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        files.insert(file_path, contents);
+    }
+    Ok(files)
+}
+
+/// Read every file in a tar archive.
+///
+/// # Errors
+/// Will return Err if the archive or any file in it cannot be read.
+fn read_tar(reader: impl Read) -> io::Result<HashMap<PathBuf, Vec<u8>>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut files = HashMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let Some(file_path) = clean_path(&entry.path()?) else {
+            continue;
+        };
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        files.insert(file_path, contents);
+    }
+    Ok(files)
+}
+
+/// Normalize a path inside an archive so it can be looked up by the relative
+/// paths templates use. Returns None for paths that escape the archive root.
+fn clean_path(file_path: &Path) -> Option<PathBuf> {
+    file_path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{fine_template, named_font_template, write_fine_template, TEMPLATES};
+    use crate::{AutomemeError, TemplateStore};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use serde_json::{json, Value};
+    use std::fs::{read, write, File};
+    use std::io::Write;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    /// The bundled this-is-fine template, reading its files from the given
+    /// paths.
+    fn pack_template(image_path: &str, font_path: &str, end_x: u32) -> Value {
+        let mut template = fine_template(end_x);
+        template["image_path"] = image_path.into();
+        template["font_path"] = font_path.into();
+        template
+    }
+
+    /// The bundled this-is-fine template, finding its font by name and
+    /// reading its image from the pack.
+    fn pack_named_font_template(font: &str) -> Value {
+        let mut template = named_font_template(font);
+        template["image_path"] = "this-is-fine.jpg".into();
+        template
+    }
+
+    /// Every file in a pack containing the given templates.
+    fn pack_files(template_names: &[&str], end_x: u32) -> Vec<(String, Vec<u8>)> {
+        let mut files = vec![
+            (
+                "this-is-fine.jpg".to_owned(),
+                read(format!("{TEMPLATES}/this-is-fine.jpg")).unwrap(),
+            ),
+            (
+                "fonts/Anton-Regular.ttf".to_owned(),
+                read(format!("{TEMPLATES}/fonts/Anton-Regular.ttf")).unwrap(),
+            ),
+        ];
+        for template_name in template_names {
+            files.push((
+                format!("{template_name}.json"),
+                pack_template("this-is-fine.jpg", "fonts/Anton-Regular.ttf", end_x)
+                    .to_string()
+                    .into_bytes(),
+            ));
+        }
+        files
+    }
+
+    fn write_zip(pack_path: &Path, files: &[(String, Vec<u8>)]) {
+        let mut zip = zip::ZipWriter::new(File::create(pack_path).unwrap());
+        for (file_name, contents) in files {
+            zip.start_file(file_name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn write_tar_gz(pack_path: &Path, files: &[(String, Vec<u8>)]) {
+        let encoder = GzEncoder::new(File::create(pack_path).unwrap(), Compression::default());
+        let mut tar = tar::Builder::new(encoder);
+        for (file_name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, format!("./{file_name}"), contents.as_slice())
+                .unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn test_packs_merge_in_order() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        write_fine_template(root, "fine", 240);
+        write_zip(
+            &root.join("first.zip"),
+            &pack_files(&["fine", "zipped"], 200),
+        );
+        write_tar_gz(
+            &root.join("second.tar.gz"),
+            &pack_files(&["zipped", "tarred"], 150),
+        );
+
+        let store = TemplateStore::new(root)
+            .with_pack(root.join("first.zip"))
+            .unwrap()
+            .with_pack(root.join("second.tar.gz"))
+            .unwrap();
+        assert_eq!(
+            store.get_template_names().unwrap(),
+            ["fine", "tarred", "zipped"]
+        );
+
+        // Earlier sources win, and each template reads its files from its own pack
+        let end_x = |name| store.get_template_from_disk(name).unwrap().text_fields[0].end[0];
+        assert_eq!(end_x("fine"), 240);
+        assert_eq!(end_x("zipped"), 200);
+        assert_eq!(end_x("tarred"), 150);
+
+        let collisions = store.get_name_collisions().unwrap();
+        assert_eq!(
+            collisions,
+            [
+                (
+                    "fine".to_owned(),
+                    vec![root.join("fine.json"), root.join("first.zip/fine.json")]
+                ),
+                (
+                    "zipped".to_owned(),
+                    vec![
+                        root.join("first.zip/zipped.json"),
+                        root.join("second.tar.gz/zipped.json")
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pack_paths_stay_inside_pack() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let mut files = pack_files(&["fine"], 240);
+        let absolute_image = format!("{TEMPLATES}/this-is-fine.jpg");
+        for (template_name, template) in [
+            (
+                "absolute",
+                pack_template(&absolute_image, "fonts/Anton-Regular.ttf", 240),
+            ),
+            (
+                "parent",
+                pack_template("this-is-fine.jpg", "../fonts/Anton-Regular.ttf", 240),
+            ),
+            (
+                "named",
+                pack_named_font_template("../../fonts/Anton-Regular"),
+            ),
+        ] {
+            files.push((
+                format!("{template_name}.json"),
+                template.to_string().into_bytes(),
+            ));
+        }
+        write_zip(&root.join("pack.zip"), &files);
+
+        // A path out of the pack would be read from the disk, so it is refused
+        // even if the file is there
+        let store = TemplateStore::from_pack(root.join("pack.zip")).unwrap();
+        for (template_name, path) in [
+            ("absolute", absolute_image.as_str()),
+            ("parent", "../fonts/Anton-Regular.ttf"),
+            ("named", "../../fonts/Anton-Regular"),
+        ] {
+            let Err(AutomemeError::PathOutsideSource { path: found, .. }) =
+                store.get_template_from_disk(template_name)
+            else {
+                panic!("{template_name} read a file outside of the pack");
+            };
+            assert_eq!(found, path);
+            assert!(!store.validate_template(template_name).is_valid());
+        }
+        assert!(store.get_template_from_disk("fine").is_ok());
+    }
+
+    #[test]
+    fn test_extends_template_from_pack() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let mut files = pack_files(&[], 0);
+        files.push((
+            "fonts/Gabarito-Bold.ttf".to_owned(),
+            read(format!("{TEMPLATES}/fonts/Gabarito-Bold.ttf")).unwrap(),
+        ));
+        let mut packed = pack_named_font_template("Anton-Regular");
+        packed["fallback_fonts"] = json!(["Gabarito-Bold"]);
+        packed["text_fields"][0]["font"] = "fonts/Gabarito-Bold.ttf".into();
+        files.push(("packed.json".to_owned(), packed.to_string().into_bytes()));
+        write_zip(&root.join("pack.zip"), &files);
+        write(root.join("variant.json"), r#"{ "extends": "packed" }"#).unwrap();
+
+        // Font names and paths from the pack still find the pack's fonts
+        let store = TemplateStore::new(root)
+            .with_pack(root.join("pack.zip"))
+            .unwrap();
+        let template = store.get_template_from_disk("variant").unwrap();
+        assert_eq!(template.fallback_fonts.len(), 1);
+        assert_eq!(template.field_fonts.len(), 1);
+        let files = store.get_template_files("variant").unwrap();
+        for file_name in ["fonts/Anton-Regular.ttf", "fonts/Gabarito-Bold.ttf"] {
+            assert!(files.contains(&root.join("pack.zip").join(file_name)));
+        }
+    }
+}
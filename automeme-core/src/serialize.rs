@@ -0,0 +1,390 @@
+//! Writing templates back out as JSON, in the canonical layout of the bundled
+//! template files.
+
+use crate::{AutomemeError, TemplateJSON};
+use serde::Serialize;
+use serde_json::ser::Formatter;
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+
+/// Indentation for each level of nesting.
+const INDENT: &[u8] = b"    ";
+
+impl TemplateJSON {
+    /// Serialize the template as JSON in the canonical form: keys in the
+    /// order the fields are declared, four spaces of indentation, and lists
+    /// of numbers or strings kept on one line. Unset optional fields are left
+    /// out. The bundled template files are all in this form, so reading one
+    /// and writing it back gives the same file.
+    ///
+    /// A template read from the store that extends another has already been
+    /// merged with it, so only what its own file sets is written, in the
+    /// same order. Anything it inherits is left out, changes to it included.
+    ///
+    /// # Errors
+    /// Will return Err if the template cannot be converted to JSON, such as
+    /// when it holds a number that is not finite.
+    pub fn to_json_string(&self) -> Result<String, AutomemeError> {
+        let mut json = Vec::new();
+        let mut serializer =
+            serde_json::Serializer::with_formatter(&mut json, CanonicalFormatter::default());
+        match &self.file_value {
+            Some(file_value) if self.extends.is_some() => {
+                let merged = serde_json::to_value(self).map_err(AutomemeError::Serialize)?;
+                own_values(&merged, file_value).serialize(&mut serializer)
+            }
+            _ => self.serialize(&mut serializer),
+        }
+        .map_err(AutomemeError::Serialize)?;
+        String::from_utf8(json).map_err(|e| AutomemeError::Serialize(serde::ser::Error::custom(e)))
+    }
+}
+
+/// The values a template's own file sets, with the keys of each object in the
+/// order they have in the merged template, and any it leaves out after them.
+/// Values are taken from the file as they are, so numbers are written the
+/// way they were read.
+fn own_values(merged: &Value, file_value: &Value) -> Value {
+    match (merged, file_value) {
+        (Value::Object(merged), Value::Object(file_value)) => {
+            let mut own: Map<String, Value> = merged
+                .iter()
+                .filter_map(|(key, merged)| {
+                    let value = file_value.get(key)?;
+                    Some((key.clone(), own_values(merged, value)))
+                })
+                .collect();
+            for (key, value) in file_value {
+                if !own.contains_key(key) {
+                    own.insert(key.clone(), value.clone());
+                }
+            }
+            Value::Object(own)
+        }
+        (Value::Array(merged), Value::Array(file_value)) => Value::Array(
+            file_value
+                .iter()
+                .enumerate()
+                .map(|(index, value)| match merged.get(index) {
+                    Some(merged) => own_values(merged, value),
+                    None => value.clone(),
+                })
+                .collect(),
+        ),
+        (_, file_value) => file_value.clone(),
+    }
+}
+
+/// The error for a number JSON cannot hold, which `serde_json` passes on to
+/// the formatter as null.
+fn not_finite() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "templates cannot hold numbers that are not finite",
+    )
+}
+
+/// Write integers of each type as values, after whatever goes before them.
+macro_rules! write_integers {
+    ($($method:ident: $integer:ty),*) => {
+        $(
+            fn $method<W: ?Sized + Write>(
+                &mut self,
+                writer: &mut W,
+                value: $integer,
+            ) -> io::Result<()> {
+                self.start_value(writer, false)?;
+                write!(writer, "{value}")
+            }
+        )*
+    };
+}
+
+/// Formats JSON with each object key and each object in a list on its own
+/// line, and lists of numbers or strings on one line.
+#[derive(Default)]
+struct CanonicalFormatter {
+    /// For each object or list being written, whether its contents go on
+    /// their own lines, which is only known once its first item starts
+    nesting: Vec<Option<bool>>,
+    /// Whether an item of the innermost list is about to start, and if it is
+    /// the first item
+    pending_item: Option<bool>,
+}
+
+impl CanonicalFormatter {
+    /// Start a new line at the indentation of the innermost object or list.
+    fn write_line_start<W: ?Sized + Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(b"\n")?;
+        for _ in 0..self.nesting.len() {
+            writer.write_all(INDENT)?;
+        }
+        Ok(())
+    }
+
+    /// Write what goes before a value. A list goes on multiple lines if its
+    /// first item is an object or a list.
+    fn start_value<W: ?Sized + Write>(&mut self, writer: &mut W, nested: bool) -> io::Result<()> {
+        let Some(first) = self.pending_item.take() else {
+            return Ok(());
+        };
+        let multiline = match self.nesting.last_mut() {
+            Some(multiline) => *multiline.get_or_insert(nested),
+            None => false,
+        };
+        if !first {
+            writer.write_all(if multiline { b"," } else { b", " })?;
+        }
+        if multiline {
+            self.write_line_start(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Close the innermost object or list, on a line of its own if its
+    /// contents were.
+    fn end_nesting<W: ?Sized + Write>(&mut self, writer: &mut W, close: &[u8]) -> io::Result<()> {
+        if self.nesting.pop() == Some(Some(true)) {
+            self.write_line_start(writer)?;
+        }
+        writer.write_all(close)
+    }
+}
+
+impl Formatter for CanonicalFormatter {
+    // Unset values are left out of templates, so the only nulls are the
+    // numbers that are not finite
+    fn write_null<W: ?Sized + Write>(&mut self, _writer: &mut W) -> io::Result<()> {
+        Err(not_finite())
+    }
+
+    fn write_bool<W: ?Sized + Write>(&mut self, writer: &mut W, value: bool) -> io::Result<()> {
+        self.start_value(writer, false)?;
+        write!(writer, "{value}")
+    }
+
+    write_integers!(
+        write_u8: u8,
+        write_u16: u16,
+        write_u32: u32,
+        write_u64: u64,
+        write_u128: u128,
+        write_i8: i8,
+        write_i16: i16,
+        write_i32: i32,
+        write_i64: i64,
+        write_i128: i128
+    );
+
+    // Floats are printed as short as they can be, without a trailing ".0" on
+    // whole sizes
+    fn write_f32<W: ?Sized + Write>(&mut self, writer: &mut W, value: f32) -> io::Result<()> {
+        if !value.is_finite() {
+            return Err(not_finite());
+        }
+        self.start_value(writer, false)?;
+        write!(writer, "{value}")
+    }
+
+    fn write_f64<W: ?Sized + Write>(&mut self, writer: &mut W, value: f64) -> io::Result<()> {
+        if !value.is_finite() {
+            return Err(not_finite());
+        }
+        self.start_value(writer, false)?;
+        write!(writer, "{value}")
+    }
+
+    fn write_number_str<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        value: &str,
+    ) -> io::Result<()> {
+        self.start_value(writer, false)?;
+        writer.write_all(value.as_bytes())
+    }
+
+    fn write_raw_fragment<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        fragment: &str,
+    ) -> io::Result<()> {
+        self.start_value(writer, false)?;
+        writer.write_all(fragment.as_bytes())
+    }
+
+    fn begin_string<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.start_value(writer, false)?;
+        writer.write_all(b"\"")
+    }
+
+    fn begin_array<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.start_value(writer, true)?;
+        self.nesting.push(None);
+        writer.write_all(b"[")
+    }
+
+    fn begin_array_value<W: ?Sized + Write>(
+        &mut self,
+        _writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        self.pending_item = Some(first);
+        Ok(())
+    }
+
+    fn end_array<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.end_nesting(writer, b"]")
+    }
+
+    fn begin_object<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.start_value(writer, true)?;
+        self.nesting.push(None);
+        writer.write_all(b"{")
+    }
+
+    fn begin_object_key<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        if let Some(multiline) = self.nesting.last_mut() {
+            *multiline = Some(true);
+        }
+        if !first {
+            writer.write_all(b",")?;
+        }
+        self.write_line_start(writer)
+    }
+
+    fn begin_object_value<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(b": ")
+    }
+
+    fn end_object<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.end_nesting(writer, b"}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CanonicalFormatter;
+    use crate::store::parse_template;
+    use crate::test_util::{write_fine_template, TEMPLATES};
+    use crate::{AutomemeError, TemplateJSON, TemplateStore};
+    use glob::glob;
+    use serde::Serialize;
+    use std::fs::{read_to_string, write};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_round_trip_every_template() {
+        let pattern = Path::new(TEMPLATES).join("*.json");
+        for file_path in glob(&pattern.to_string_lossy()).unwrap() {
+            let file_path = file_path.unwrap();
+            let text = read_to_string(&file_path).unwrap();
+            let template_json: TemplateJSON = parse_template(&file_path, &text).unwrap();
+            assert_eq!(
+                template_json.to_json_string().unwrap(),
+                text,
+                "{} changed when written back",
+                file_path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn test_modified_template_serializes_in_order() {
+        let file_path = Path::new(TEMPLATES).join("pikachu.json");
+        let mut template_json: TemplateJSON =
+            parse_template(&file_path, &read_to_string(&file_path).unwrap()).unwrap();
+        template_json.metadata.tags = vec!["reaction".to_owned()];
+        template_json.text_fields[0].text_size = 72.5;
+        template_json.text_fields[0].border_color = None;
+
+        let json = template_json.to_json_string().unwrap();
+        assert!(json.starts_with(
+            "{\n    \"title\": \"Surprised Pikachu\",\n    \"tags\": [\"reaction\"],\n"
+        ));
+        assert!(json.contains("\"text_size\": 72.5,\n"));
+        assert!(!json.contains("border_color"));
+
+        let reparsed: TemplateJSON = parse_template(&file_path, &json).unwrap();
+        assert_eq!(reparsed.to_json_string().unwrap(), json);
+    }
+
+    #[test]
+    fn test_extending_template_writes_its_own_keys() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        write_fine_template(root, "base", 240);
+        write(
+            root.join("variant.json"),
+            r#"{
+                "text_fields": [{ "text_size": 0.1, "text": "changed" }],
+                "title": "Variant",
+                "extends": "base"
+            }"#,
+        )
+        .unwrap();
+
+        // Nothing taken from the base is written, and the keys that are
+        // written are in the canonical order
+        let store = TemplateStore::new(root);
+        let template_json = store.get_json_from_disk("variant").unwrap();
+        assert_eq!(template_json.text_fields[0].end, [240, 70]);
+        assert_eq!(
+            template_json.to_json_string().unwrap(),
+            concat!(
+                "{\n",
+                "    \"extends\": \"base\",\n",
+                "    \"title\": \"Variant\",\n",
+                "    \"text_fields\": [\n",
+                "        {\n",
+                "            \"text\": \"changed\",\n",
+                "            \"text_size\": 0.1\n",
+                "        }\n",
+                "    ]\n",
+                "}"
+            )
+        );
+    }
+
+    #[test]
+    fn test_numbers_are_written_as_values() {
+        #[derive(Serialize)]
+        struct Numbers {
+            small: i8,
+            medium: i32,
+            wide: u16,
+            list: Vec<i16>,
+        }
+
+        let numbers = Numbers {
+            small: -1,
+            medium: 2,
+            wide: 3,
+            list: vec![4, -5],
+        };
+        let mut json = Vec::new();
+        let mut serializer =
+            serde_json::Serializer::with_formatter(&mut json, CanonicalFormatter::default());
+        numbers.serialize(&mut serializer).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\n    \"small\": -1,\n    \"medium\": 2,\n    \"wide\": 3,\n    \"list\": [4, -5]\n}"
+        );
+
+        // JSON has no way to write numbers that are not finite
+        let file_path = Path::new(TEMPLATES).join("pikachu.json");
+        let mut template_json: TemplateJSON =
+            parse_template(&file_path, &read_to_string(&file_path).unwrap()).unwrap();
+        for text_size in [f32::NAN, f32::INFINITY] {
+            template_json.text_fields[0].text_size = text_size;
+            assert!(matches!(
+                template_json.to_json_string(),
+                Err(AutomemeError::Serialize(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,497 @@
+//! A shared set of loaded templates that can be swapped out while in use.
+
+#[cfg(feature = "watch")]
+use crate::watch::WatchMessage;
+use crate::{AutomemeError, Template, TemplateStore, ValidationReport};
+use std::collections::HashMap;
+use std::fs::canonicalize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "watch")]
+use std::sync::mpsc::Sender;
+#[cfg(feature = "watch")]
+use std::sync::Mutex;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A loaded template along with every file it was loaded from, so it can be
+/// found again when one of those files changes.
+#[derive(Debug)]
+struct LoadedTemplate {
+    template: Arc<Template>,
+    files: Vec<PathBuf>,
+    last_used: AtomicU64,
+}
+
+/// The loaded templates, and for each template name how many times it has
+/// been reloaded. Templates are read from the disk without holding the lock,
+/// so a load only goes in if no reload started while it was being read.
+#[derive(Debug, Default)]
+struct Templates {
+    loaded: HashMap<String, Arc<LoadedTemplate>>,
+    generations: HashMap<String, u64>,
+}
+
+impl Templates {
+    /// How many times a template has been reloaded.
+    fn generation(&self, template_name: &str) -> u64 {
+        self.generations.get(template_name).copied().unwrap_or(0)
+    }
+}
+
+/// Templates loaded from a store, kept in memory and shared between threads.
+/// Templates are loaded the first time they are requested. Replacing a
+/// template swaps its entry at once, so readers always see either the old
+/// version or the new one.
+///
+/// By default every template stays loaded once requested. A set created
+/// with a capacity drops the least recently used template when it is full.
+#[derive(Debug)]
+pub struct TemplateSet {
+    store: TemplateStore,
+    templates: RwLock<Templates>,
+    capacity: Option<usize>,
+    clock: AtomicU64,
+    /// Where to send the files of each loaded template while it is watched
+    #[cfg(feature = "watch")]
+    watch_sender: Mutex<Option<Sender<WatchMessage>>>,
+}
+
+impl TemplateSet {
+    /// Create an empty set that loads templates from the given store.
+    pub fn new(store: TemplateStore) -> Self {
+        TemplateSet {
+            store,
+            templates: RwLock::new(Templates::default()),
+            capacity: None,
+            clock: AtomicU64::new(0),
+            #[cfg(feature = "watch")]
+            watch_sender: Mutex::new(None),
+        }
+    }
+
+    /// Keep at most `capacity` templates loaded, dropping the least recently
+    /// used template to make room for a new one.
+    #[must_use]
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        TemplateSet {
+            capacity: Some(capacity.max(1)),
+            ..self
+        }
+    }
+
+    /// The number of templates currently loaded.
+    pub fn len(&self) -> usize {
+        self.read().loaded.len()
+    }
+
+    /// Whether no templates are currently loaded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check whether a template is loaded, without loading it.
+    pub fn is_loaded(&self, template_name: &str) -> bool {
+        self.read().loaded.contains_key(template_name)
+    }
+
+    /// Mark a template as used and return it.
+    fn touch(&self, loaded: &LoadedTemplate) -> Arc<Template> {
+        loaded.last_used.store(
+            self.clock.fetch_add(1, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        Arc::clone(&loaded.template)
+    }
+
+    /// The store templates are loaded from.
+    pub fn store(&self) -> &TemplateStore {
+        &self.store
+    }
+
+    /// Lock the loaded templates for reading.
+    fn read(&self) -> RwLockReadGuard<'_, Templates> {
+        self.templates
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the loaded templates for writing.
+    fn write(&self) -> RwLockWriteGuard<'_, Templates> {
+        self.templates
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Get a loaded template, marking it as used.
+    fn get_loaded(&self, template_name: &str) -> Option<Arc<Template>> {
+        let templates = self.read();
+        let loaded = templates.loaded.get(template_name)?;
+        Some(self.touch(loaded))
+    }
+
+    /// Start reloading a template, so any load of it already under way is not
+    /// put in the set once it finishes. Returns the generation the reload
+    /// must still be current in to be put in.
+    fn start_reload(&self, template_name: &str) -> u64 {
+        let mut templates = self.write();
+        let generation = templates.generation(template_name) + 1;
+        templates
+            .generations
+            .insert(template_name.to_owned(), generation);
+        generation
+    }
+
+    /// Put a template that was loaded in a generation in the set, replacing
+    /// the loaded version or removing it if there is none. Nothing changes if
+    /// the template was reloaded since then. With `keep_loaded`, a template
+    /// that is already loaded is kept as well, as when two requests load it at
+    /// once. Returns the template that is in the set afterwards.
+    fn put(
+        &self,
+        template_name: &str,
+        loaded: Option<LoadedTemplate>,
+        generation: u64,
+        keep_loaded: bool,
+    ) -> Option<Arc<Template>> {
+        let mut templates = self.write();
+        let reloaded = templates.generation(template_name) != generation;
+        let current = templates.loaded.get(template_name);
+        if reloaded || (keep_loaded && current.is_some()) {
+            return current.map(|loaded| self.touch(loaded));
+        }
+        let Some(loaded) = loaded else {
+            templates.loaded.remove(template_name);
+            return None;
+        };
+        let template = Arc::clone(&loaded.template);
+        templates
+            .loaded
+            .insert(template_name.to_owned(), Arc::new(loaded));
+
+        // Make room by dropping the least recently used templates
+        if let Some(capacity) = self.capacity {
+            while templates.loaded.len() > capacity {
+                let Some(oldest) = templates
+                    .loaded
+                    .iter()
+                    .filter(|(name, _)| name.as_str() != template_name)
+                    .min_by_key(|(_, loaded)| loaded.last_used.load(Ordering::Relaxed))
+                    .map(|(name, _)| name.clone())
+                else {
+                    break;
+                };
+                templates.loaded.remove(&oldest);
+            }
+        }
+        Some(template)
+    }
+
+    /// Load a template and the canonical paths of all of its files.
+    ///
+    /// # Errors
+    /// Will return Err if the store cannot load the template or find its files.
+    fn load(&self, template_name: &str) -> Result<LoadedTemplate, AutomemeError> {
+        let template = self.store.get_template_from_disk(template_name)?;
+        self.with_files(template_name, template)
+    }
+
+    /// Load a template that is not in the set yet and put it in, unless it was
+    /// loaded or reloaded in the meantime, in which case that version is used.
+    ///
+    /// # Errors
+    /// Will return Err if the store cannot load the template or find its files.
+    fn load_missing(&self, template_name: &str) -> Result<Arc<Template>, AutomemeError> {
+        let generation = self.read().generation(template_name);
+        let loaded = self.load(template_name)?;
+        let template = Arc::clone(&loaded.template);
+        Ok(self
+            .put(template_name, Some(loaded), generation, true)
+            .unwrap_or(template))
+    }
+
+    /// Pair an already loaded template with the canonical paths of all of its
+    /// files.
+    ///
+    /// # Errors
+    /// Will return Err if the store cannot find the template's files.
+    fn with_files(
+        &self,
+        template_name: &str,
+        template: Template,
+    ) -> Result<LoadedTemplate, AutomemeError> {
+        let files: Vec<PathBuf> = self
+            .store
+            .get_template_files(template_name)?
+            .into_iter()
+            .map(|file_path| canonical_path(&file_path))
+            .collect();
+        #[cfg(feature = "watch")]
+        self.send_loaded(&files);
+        Ok(LoadedTemplate {
+            template: Arc::new(template),
+            files,
+            last_used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+        })
+    }
+
+    /// Send the files of a template that was just loaded to the watcher, if
+    /// the set is being watched.
+    #[cfg(feature = "watch")]
+    fn send_loaded(&self, files: &[PathBuf]) {
+        let watch_sender = self
+            .watch_sender
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(watch_sender) = watch_sender.as_ref() {
+            let _ = watch_sender.send(WatchMessage::Loaded(files.to_vec()));
+        }
+    }
+
+    /// Start or stop sending the files of each loaded template to a watcher.
+    #[cfg(feature = "watch")]
+    pub(crate) fn set_watch_sender(&self, watch_sender: Option<Sender<WatchMessage>>) {
+        *self
+            .watch_sender
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = watch_sender;
+    }
+
+    /// The canonical paths of the files of every loaded template.
+    #[cfg(feature = "watch")]
+    pub(crate) fn loaded_files(&self) -> Vec<PathBuf> {
+        self.read()
+            .loaded
+            .values()
+            .flat_map(|loaded| loaded.files.iter().cloned())
+            .collect()
+    }
+
+    /// Get a template by name or alias, loading it from the store if it has
+    /// not been requested before.
+    ///
+    /// # Errors
+    /// Will return Err if the template is not loaded yet and the store
+    /// cannot load it.
+    pub fn get_template(&self, template_name: &str) -> Result<Arc<Template>, AutomemeError> {
+        if let Some(template) = self.get_loaded(template_name) {
+            return Ok(template);
+        }
+        let template_name = self.store.resolve_template_name(template_name)?;
+        if let Some(template) = self.get_loaded(&template_name) {
+            return Ok(template);
+        }
+        self.load_missing(&template_name)
+    }
+
+    /// Load every template in the store up front, so no request has to wait
+    /// for one to be read from the disk. If the set has a capacity, only that
+    /// many templates are loaded. Returns the number of templates loaded.
+    ///
+    /// # Errors
+    /// Will return Err if the template names cannot be read or any template
+    /// fails to load.
+    pub fn preload(&self) -> Result<usize, AutomemeError> {
+        let template_names = self.store.get_template_names()?;
+        let count = self.capacity.map_or(template_names.len(), |capacity| {
+            capacity.min(template_names.len())
+        });
+        for template_name in &template_names[..count] {
+            self.load_missing(template_name)?;
+        }
+        Ok(count)
+    }
+
+    /// Validate a loaded template against the files in the store and swap in
+    /// the new version if it passes. If it fails, the previous version is
+    /// kept. If the template no longer exists it is removed from the set.
+    pub fn reload_template(&self, template_name: &str) -> ValidationReport {
+        let generation = self.start_reload(template_name);
+        self.store.forget_template_files();
+
+        // Make sure the font is read from the disk again in case it changed
+        for file_path in self
+            .store
+            .get_template_files(template_name)
+            .unwrap_or_default()
+        {
+            self.store.fonts().forget_font(&file_path);
+        }
+
+        let (mut report, template) = self.store.validate_and_load_template(template_name);
+        if let Err(AutomemeError::TemplateNotFound(_)) =
+            self.store.resolve_template_name(template_name)
+        {
+            self.put(template_name, None, generation, false);
+            return report;
+        }
+
+        // Swap in the template decoded during validation instead of reading
+        // it all again
+        if let Some(template) = template {
+            match self.with_files(template_name, template) {
+                Ok(loaded) => {
+                    self.put(template_name, Some(loaded), generation, false);
+                }
+                Err(e) => report.errors.push(e.to_string()),
+            }
+        }
+        report
+    }
+
+    /// Reload every loaded template that depends on the given file. Templates
+    /// that have not been loaded yet will pick up the change when requested.
+    pub fn reload_file(&self, file_path: &Path) -> Vec<ValidationReport> {
+        // The file may add, remove, or rename a template that is not loaded
+        self.store.forget_template_files();
+        let file_path = canonical_path(file_path);
+        let template_names: Vec<String> = self
+            .read()
+            .loaded
+            .iter()
+            .filter(|(_, loaded)| loaded.files.contains(&file_path))
+            .map(|(name, _)| name.clone())
+            .collect();
+        template_names
+            .iter()
+            .map(|template_name| self.reload_template(template_name))
+            .collect()
+    }
+}
+
+/// Canonicalize the directory holding a file and join the file name back on,
+/// so a file that has just been removed still maps to the path it was loaded
+/// from. Paths that cannot be canonicalized are used as given.
+fn canonical_path(file_path: &Path) -> PathBuf {
+    file_path
+        .parent()
+        .zip(file_path.file_name())
+        .and_then(|(parent, file_name)| Some(canonicalize(parent).ok()?.join(file_name)))
+        .unwrap_or_else(|| file_path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fine_template, write_fine_template, write_template};
+    use serde_json::json;
+    use std::fs::{create_dir, remove_file};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_reload_keeps_previous_version_on_bad_edit() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        write_fine_template(root, "fine", 240);
+        let templates = TemplateSet::new(TemplateStore::new(root));
+        assert_eq!(
+            templates.get_template("fine").unwrap().text_fields[0].end[0],
+            240
+        );
+
+        // A field that ends before it starts fails validation, so the old
+        // version is kept
+        write_fine_template(root, "fine", 5);
+        let reports = templates.reload_file(&root.join("fine.json"));
+        assert_eq!(reports.len(), 1);
+        assert!(!reports[0].is_valid());
+        assert_eq!(
+            templates.get_template("fine").unwrap().text_fields[0].end[0],
+            240
+        );
+
+        // Fixing the file swaps in the new version
+        write_fine_template(root, "fine", 200);
+        assert!(templates.reload_template("fine").is_valid());
+        assert_eq!(
+            templates.get_template("fine").unwrap().text_fields[0].end[0],
+            200
+        );
+    }
+
+    #[test]
+    fn test_load_started_before_reload_is_dropped() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        write_fine_template(root, "fine", 240);
+        let templates = TemplateSet::new(TemplateStore::new(root));
+
+        // A request starts loading the template before it is edited and
+        // reloaded, and finishes after
+        let generation = templates.read().generation("fine");
+        let stale = templates.load("fine").unwrap();
+        write_fine_template(root, "fine", 200);
+        assert!(templates.reload_template("fine").is_valid());
+        let template = templates
+            .put("fine", Some(stale), generation, true)
+            .unwrap();
+        assert_eq!(template.text_fields[0].end[0], 200);
+        assert_eq!(
+            templates.get_template("fine").unwrap().text_fields[0].end[0],
+            200
+        );
+    }
+
+    #[test]
+    fn test_reload_drops_deleted_template() {
+        let temp_dir = tempdir().unwrap();
+        create_dir(temp_dir.path().join("templates")).unwrap();
+
+        // Watched paths are not always canonical, like a root given on the
+        // command line
+        let root = temp_dir.path().join("templates/../templates");
+        write_fine_template(&root, "fine", 240);
+        let templates = TemplateSet::new(TemplateStore::new(&root));
+        templates.get_template("fine").unwrap();
+
+        // The deleted file can no longer be canonicalized, but must still
+        // match the file the template was loaded from
+        remove_file(root.join("fine.json")).unwrap();
+        templates.reload_file(&root.join("fine.json"));
+        assert!(!templates.is_loaded("fine"));
+        assert!(matches!(
+            templates.get_template("fine"),
+            Err(AutomemeError::TemplateNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_capacity_drops_least_recently_used() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        for template_name in ["one", "two", "three"] {
+            write_fine_template(root, template_name, 240);
+        }
+        let templates = TemplateSet::new(TemplateStore::new(root)).with_capacity(2);
+        templates.get_template("one").unwrap();
+        templates.get_template("two").unwrap();
+        templates.get_template("one").unwrap();
+        templates.get_template("three").unwrap();
+        assert_eq!(templates.len(), 2);
+        assert!(templates.is_loaded("one"));
+        assert!(!templates.is_loaded("two"));
+        assert!(templates.is_loaded("three"));
+    }
+
+    #[test]
+    fn test_aliases_follow_reloads() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let write_aliased = |alias: &str| {
+            let mut template = fine_template(240);
+            template["aliases"] = json!([alias]);
+            write_template(root, "fine", &template);
+        };
+        write_aliased("okay");
+        let templates = TemplateSet::new(TemplateStore::new(root));
+        assert!(templates.get_template("okay").is_ok());
+
+        // Aliases are read again once the template file changes
+        write_aliased("alright");
+        templates.reload_file(&root.join("fine.json"));
+        assert!(templates.get_template("alright").is_ok());
+        assert!(matches!(
+            templates.get_template("okay"),
+            Err(AutomemeError::TemplateNotFound(_))
+        ));
+    }
+}
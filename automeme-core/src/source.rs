@@ -0,0 +1,225 @@
+//! Where a template store reads its files from: a directory on the disk, an
+//! archive loaded into memory, or files built into the binary.
+
+use crate::store::TEMPLATE_EXTENSIONS;
+use crate::AutomemeError;
+use glob::{glob, Pattern};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::fs::read;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+#[cfg(feature = "packs")]
+use std::collections::HashMap;
+#[cfg(feature = "packs")]
+use std::sync::Arc;
+
+/// Root that paths to embedded files are resolved against.
+#[cfg(feature = "embed")]
+const EMBEDDED_ROOT: &str = "<embedded>";
+
+/// The bundled templates directory, built into the binary.
+#[cfg(feature = "embed")]
+static EMBEDDED_TEMPLATES: include_dir::Dir<'static> =
+    include_dir::include_dir!("$CARGO_MANIFEST_DIR/../templates");
+
+/// A source that reads any path straight from the disk.
+pub(crate) fn disk() -> &'static TemplateSource {
+    static DISK: OnceLock<TemplateSource> = OnceLock::new();
+    DISK.get_or_init(|| TemplateSource::Directory(PathBuf::new()))
+}
+
+/// The files backing a template store. Every path handed to a source is the
+/// source's root joined with a path relative to that root.
+#[derive(Debug, Clone)]
+pub(crate) enum TemplateSource {
+    /// Files in a directory on the disk
+    Directory(PathBuf),
+    /// Files read out of an archive, keyed by their path inside the archive
+    #[cfg(feature = "packs")]
+    Archive {
+        root: PathBuf,
+        files: Arc<HashMap<PathBuf, Vec<u8>>>,
+    },
+    /// Files built into the binary
+    #[cfg(feature = "embed")]
+    Embedded(&'static include_dir::Dir<'static>),
+}
+
+impl TemplateSource {
+    /// The templates directory built into the binary.
+    #[cfg(feature = "embed")]
+    pub(crate) fn embedded() -> Self {
+        TemplateSource::Embedded(&EMBEDDED_TEMPLATES)
+    }
+
+    /// The path template files in this source are resolved against.
+    pub(crate) fn root(&self) -> &Path {
+        match self {
+            TemplateSource::Directory(root) => root,
+            #[cfg(feature = "packs")]
+            TemplateSource::Archive { root, .. } => root,
+            #[cfg(feature = "embed")]
+            TemplateSource::Embedded(_) => Path::new(EMBEDDED_ROOT),
+        }
+    }
+
+    /// Whether this source is a directory on the disk. Templates from any
+    /// other source may only use files inside that source.
+    pub(crate) fn is_directory(&self) -> bool {
+        matches!(self, TemplateSource::Directory(_))
+    }
+
+    /// Whether this source, rather than the disk, holds the file at a path.
+    pub(crate) fn holds(&self, file_path: &Path) -> bool {
+        !self.is_directory() && file_path.starts_with(self.root())
+    }
+
+    /// Get the names of every template file directly inside the root, sorted.
+    /// A template with files in more than one format is listed once.
+    ///
+    /// # Errors
+    /// Will return Err if:
+    /// - the root or any template filename cannot be converted to a string
+    /// - the root directory cannot be read
+    pub(crate) fn template_names(&self) -> Result<Vec<String>, AutomemeError> {
+        let root = self.root();
+        let is_template = |file_path: &Path| {
+            file_path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| TEMPLATE_EXTENSIONS.contains(&e))
+        };
+        let template_paths: Vec<PathBuf> = match self {
+            TemplateSource::Directory(_) => {
+                let root_str = root
+                    .to_str()
+                    .ok_or_else(|| AutomemeError::InvalidPath(root.to_path_buf()))?;
+                let pattern = format!("{}/*.*", Pattern::escape(root_str));
+                glob(&pattern)
+                    .map_err(|_| AutomemeError::InvalidPath(root.to_path_buf()))?
+                    .map(|path| {
+                        path.map_err(|e| AutomemeError::Io {
+                            path: e.path().to_path_buf(),
+                            source: io::Error::from(e),
+                        })
+                    })
+                    .filter(|path| path.as_ref().map_or(true, |path| is_template(path)))
+                    .collect::<Result<_, AutomemeError>>()?
+            }
+            #[cfg(feature = "packs")]
+            TemplateSource::Archive { files, .. } => files
+                .keys()
+                .filter(|file_path| file_path.parent() == Some(Path::new("")))
+                .filter(|file_path| is_template(file_path))
+                .cloned()
+                .collect(),
+            #[cfg(feature = "embed")]
+            TemplateSource::Embedded(dir) => dir
+                .files()
+                .map(|file| file.path().to_path_buf())
+                .filter(|file_path| is_template(file_path))
+                .collect(),
+        };
+        let names = template_paths
+            .into_iter()
+            .map(|file_path| {
+                file_path
+                    .file_stem()
+                    .and_then(|file_stem| file_stem.to_str())
+                    .map(str::to_owned)
+                    .ok_or_else(|| AutomemeError::InvalidPath(file_path.clone()))
+            })
+            .collect::<Result<BTreeSet<String>, AutomemeError>>()?;
+        Ok(names.into_iter().collect())
+    }
+
+    /// Check whether a file exists.
+    pub(crate) fn is_file(&self, file_path: &Path) -> bool {
+        match self {
+            TemplateSource::Directory(_) => file_path.is_file(),
+            #[cfg(feature = "packs")]
+            TemplateSource::Archive { root, files } => file_path
+                .strip_prefix(root)
+                .is_ok_and(|relative_path| files.contains_key(relative_path)),
+            #[cfg(feature = "embed")]
+            TemplateSource::Embedded(dir) => file_path
+                .strip_prefix(EMBEDDED_ROOT)
+                .is_ok_and(|relative_path| dir.get_file(relative_path).is_some()),
+        }
+    }
+
+    /// Read the contents of a file.
+    ///
+    /// # Errors
+    /// Will return Err if the file does not exist or cannot be read.
+    pub(crate) fn read(&self, file_path: &Path) -> Result<Cow<'_, [u8]>, AutomemeError> {
+        match self {
+            TemplateSource::Directory(_) => {
+                read(file_path)
+                    .map(Cow::Owned)
+                    .map_err(|source| AutomemeError::Io {
+                        path: file_path.to_path_buf(),
+                        source,
+                    })
+            }
+            #[cfg(feature = "packs")]
+            TemplateSource::Archive { root, files } => file_path
+                .strip_prefix(root)
+                .ok()
+                .and_then(|relative_path| files.get(relative_path))
+                .map(|contents| Cow::Borrowed(contents.as_slice()))
+                .ok_or_else(|| not_found(file_path)),
+            #[cfg(feature = "embed")]
+            TemplateSource::Embedded(dir) => file_path
+                .strip_prefix(EMBEDDED_ROOT)
+                .ok()
+                .and_then(|relative_path| dir.get_file(relative_path))
+                .map(|file| Cow::Borrowed(file.contents()))
+                .ok_or_else(|| not_found(file_path)),
+        }
+    }
+
+    /// Read a file as UTF-8 text.
+    ///
+    /// # Errors
+    /// Will return Err if the file cannot be read or is not valid UTF-8.
+    pub(crate) fn read_to_string(&self, file_path: &Path) -> Result<String, AutomemeError> {
+        let bytes = self.read(file_path)?;
+        String::from_utf8(bytes.into_owned()).map_err(|e| AutomemeError::Io {
+            path: file_path.to_path_buf(),
+            source: io::Error::new(io::ErrorKind::InvalidData, e),
+        })
+    }
+}
+
+/// The error for a file missing from an archive or the embedded templates.
+#[cfg(any(feature = "packs", feature = "embed"))]
+fn not_found(file_path: &Path) -> AutomemeError {
+    AutomemeError::Io {
+        path: file_path.to_path_buf(),
+        source: io::ErrorKind::NotFound.into(),
+    }
+}
+
+#[cfg(all(test, feature = "embed"))]
+mod tests {
+    use crate::TemplateStore;
+
+    #[test]
+    fn test_embedded_templates_match_directory() {
+        let directory = TemplateStore::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../templates"));
+        let embedded = TemplateStore::embedded();
+        assert_eq!(
+            embedded.get_template_names().unwrap(),
+            directory.get_template_names().unwrap()
+        );
+        let template = embedded.get_template_from_disk("pikachu").unwrap();
+        assert_eq!(
+            template.image,
+            directory.get_template_from_disk("pikachu").unwrap().image
+        );
+    }
+}
@@ -0,0 +1,980 @@
+//! Loading templates and their resources from a template directory.
+
+use crate::source::{disk, TemplateSource};
+use crate::transform::is_convex_quad;
+use crate::{
+    AutomemeError, FontFace, FontRegistry, Template, TemplateJSON, TemplateMetadata, TextField,
+};
+use fontdue::Font;
+use image::RgbaImage;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// Directory inside the template root where fonts can be referenced by name.
+const FONT_DIRECTORY: &str = "fonts";
+
+/// File extensions tried, in order, when looking up a font by name.
+const FONT_EXTENSIONS: [&str; 3] = ["ttf", "otf", "ttc"];
+
+/// What the corners of a warped text field must be, for error messages.
+pub(crate) const QUAD_ORDER: &str = "must form a convex quad listed clockwise from the top-left";
+
+/// File extensions of template files, in order of precedence when a template
+/// has files in more than one format.
+pub(crate) const TEMPLATE_EXTENSIONS: [&str; 4] = ["json", "toml", "yaml", "yml"];
+
+/// Just the aliases of a template, which are never inherited from the template
+/// it extends, so they can be read without resolving the whole template.
+#[derive(Deserialize)]
+struct TemplateAliases {
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+/// The name of the template each alias points to.
+type AliasMap = HashMap<String, String>;
+
+/// The metadata of every template, sorted by template name.
+type MetadataList = Vec<(String, TemplateMetadata)>;
+
+/// A directory of template files. Each template is a json, toml or yaml file
+/// in the root of the directory, and all image and font paths inside the
+/// template files are resolved relative to that same root.
+///
+/// A store can also read from several sources, such as a directory and some
+/// template packs. When more than one source has a template with the same
+/// name, the source added first wins.
+#[derive(Debug, Clone)]
+pub struct TemplateStore {
+    sources: Vec<TemplateSource>,
+    fonts: Arc<FontRegistry>,
+    fallback_fonts: Vec<PathBuf>,
+    aliases: Arc<RwLock<Option<Arc<AliasMap>>>>,
+    metadata: Arc<RwLock<Option<Arc<MetadataList>>>>,
+}
+
+impl TemplateStore {
+    /// Create a store that reads templates from the given directory.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        TemplateStore::from_source(TemplateSource::Directory(root.into()))
+    }
+
+    /// Create a store that reads the templates built into the binary. Paths
+    /// to embedded files start with `<embedded>`, and fallback fonts set with
+    /// `with_fallback_fonts` are still read from the disk.
+    #[cfg(feature = "embed")]
+    pub fn embedded() -> Self {
+        TemplateStore::from_source(TemplateSource::embedded())
+    }
+
+    /// Create a store that reads templates from a single source.
+    pub(crate) fn from_source(source: TemplateSource) -> Self {
+        TemplateStore {
+            sources: vec![source],
+            fonts: Arc::new(FontRegistry::new()),
+            fallback_fonts: Vec::new(),
+            aliases: Arc::default(),
+            metadata: Arc::default(),
+        }
+    }
+
+    /// Add a source to read templates from, used for any template the
+    /// existing sources do not have.
+    #[cfg(feature = "packs")]
+    #[must_use]
+    pub(crate) fn with_source(mut self, source: TemplateSource) -> Self {
+        self.sources.push(source);
+        self.aliases = Arc::default();
+        self.metadata = Arc::default();
+        self
+    }
+
+    /// Set fonts to try, in order, for characters that neither a template's
+    /// font nor its own fallback fonts have glyphs for. These paths are used
+    /// as given and are not resolved against the template root.
+    #[must_use]
+    pub fn with_fallback_fonts<P: Into<PathBuf>>(
+        mut self,
+        font_paths: impl IntoIterator<Item = P>,
+    ) -> Self {
+        self.fallback_fonts = font_paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// The fonts loaded so far, shared by every template from this store and
+    /// its clones.
+    pub fn fonts(&self) -> &FontRegistry {
+        &self.fonts
+    }
+
+    /// The directory this store reads templates from, or the first source
+    /// if it reads from several.
+    pub fn root(&self) -> &Path {
+        self.sources[0].root()
+    }
+
+    /// The root of each source, and whether that source is a directory on
+    /// the disk.
+    #[cfg(feature = "watch")]
+    pub(crate) fn source_roots(&self) -> impl Iterator<Item = (&Path, bool)> {
+        self.sources
+            .iter()
+            .map(|source| (source.root(), source.is_directory()))
+    }
+
+    /// Get the source holding a file, or the disk if no source holds it.
+    fn source_for(&self, file_path: &Path) -> &TemplateSource {
+        self.sources
+            .iter()
+            .find(|source| source.holds(file_path))
+            .unwrap_or_else(|| disk())
+    }
+
+    /// Check whether a file exists in any source or on the disk.
+    fn is_file(&self, file_path: &Path) -> bool {
+        self.source_for(file_path).is_file(file_path)
+    }
+
+    /// Read the contents of a file from any source or the disk.
+    ///
+    /// # Errors
+    /// Will return Err if the file does not exist or cannot be read.
+    fn read(&self, file_path: &Path) -> Result<Cow<'_, [u8]>, AutomemeError> {
+        self.source_for(file_path).read(file_path)
+    }
+
+    /// Resolve a path from a template file against the root of the source
+    /// the template was read from.
+    fn resolve_path(template_json: &TemplateJSON, relative_path: &str) -> PathBuf {
+        template_json.root.join(relative_path)
+    }
+
+    /// Get the path of every file defining a template, in order of
+    /// precedence. Returns nothing if the name could point outside of a
+    /// source's root.
+    fn template_paths(&self, template_name: &str) -> Vec<(&TemplateSource, PathBuf)> {
+        if template_name.is_empty()
+            || template_name.starts_with('.')
+            || template_name.contains(['/', '\\'])
+        {
+            return Vec::new();
+        }
+        self.sources
+            .iter()
+            .flat_map(|source| {
+                TEMPLATE_EXTENSIONS.iter().map(move |extension| {
+                    let file_path = source.root().join(format!("{template_name}.{extension}"));
+                    (source, file_path)
+                })
+            })
+            .filter(|(source, file_path)| source.is_file(file_path))
+            .collect()
+    }
+
+    /// Get the path of the file a template is read from.
+    fn template_path(&self, template_name: &str) -> Option<PathBuf> {
+        self.template_paths(template_name)
+            .into_iter()
+            .next()
+            .map(|(_, file_path)| file_path)
+    }
+
+    /// Get a list of templates based on template filenames.
+    ///
+    /// # Errors
+    /// Will return Err if:
+    /// - the template root or any template filename cannot be converted to a string
+    /// - the template directory cannot be read
+    pub fn get_template_names(&self) -> Result<Vec<String>, AutomemeError> {
+        let mut names = BTreeSet::new();
+        for source in &self.sources {
+            names.extend(source.template_names()?);
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    /// Find every template name that more than one source has. Each name is
+    /// listed with the template files that define it, starting with the one
+    /// that is used.
+    ///
+    /// # Errors
+    /// Will return Err if `get_template_names` returns Err.
+    pub fn get_name_collisions(&self) -> Result<Vec<(String, Vec<PathBuf>)>, AutomemeError> {
+        Ok(self
+            .get_template_names()?
+            .into_iter()
+            .filter_map(|name| {
+                let file_paths: Vec<PathBuf> = self
+                    .template_paths(&name)
+                    .into_iter()
+                    .map(|(_, file_path)| file_path)
+                    .collect();
+                (file_paths.len() > 1).then_some((name, file_paths))
+            })
+            .collect())
+    }
+
+    /// Find the template that lists the given name as one of its aliases.
+    /// The aliases of every template are read once and kept until
+    /// `forget_template_files` is called. Templates that fail to load are
+    /// skipped.
+    ///
+    /// # Errors
+    /// Will return Err if `get_template_names` returns Err.
+    pub fn find_template_by_alias(&self, alias: &str) -> Result<Option<String>, AutomemeError> {
+        Ok(self.alias_map()?.get(alias).cloned())
+    }
+
+    /// Get the canonical name of a template, looking it up by alias if no
+    /// template has that exact name.
+    ///
+    /// # Errors
+    /// Will return Err if no template by that name or alias exists, or if
+    /// `find_template_by_alias` returns Err.
+    pub fn resolve_template_name(&self, template_name: &str) -> Result<String, AutomemeError> {
+        if self.template_path(template_name).is_some() {
+            return Ok(template_name.to_owned());
+        }
+        self.find_template_by_alias(template_name)?
+            .ok_or_else(|| AutomemeError::TemplateNotFound(template_name.to_owned()))
+    }
+
+    /// Get the paths of every file a template depends on: the template file
+    /// itself, any templates it extends, its image, and its fonts.
+    ///
+    /// # Errors
+    /// Will return Err if `read_json_from_disk` returns Err.
+    pub fn get_template_files(&self, template_name: &str) -> Result<Vec<PathBuf>, AutomemeError> {
+        let template_name = self.resolve_template_name(template_name)?;
+        let template_json = self.read_json_from_disk(&template_name)?;
+        let mut files = template_json.files.clone();
+        files.push(TemplateStore::resolve_path(
+            &template_json,
+            &template_json.image_path,
+        ));
+        files.push(self.get_font_path(&template_name, &template_json)?);
+        files.extend(self.get_fallback_font_paths(&template_name, &template_json)?);
+        files.extend(
+            self.get_field_font_paths(&template_name, &template_json)?
+                .into_iter()
+                .map(|(_, font_path)| font_path),
+        );
+        Ok(files)
+    }
+
+    /// Get the aliases of every template, reading them from the template
+    /// files if they have not been read since they were last forgotten.
+    ///
+    /// # Errors
+    /// Will return Err if `get_template_names` returns Err.
+    fn alias_map(&self) -> Result<Arc<AliasMap>, AutomemeError> {
+        if let Some(aliases) = &*self.aliases.read().unwrap_or_else(PoisonError::into_inner) {
+            return Ok(Arc::clone(aliases));
+        }
+        let mut aliases = self.aliases.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(aliases) = &*aliases {
+            return Ok(Arc::clone(aliases));
+        }
+
+        // When templates share an alias, the first by name keeps it
+        let mut alias_map = AliasMap::new();
+        for template_name in self.get_template_names()? {
+            let Some(template_aliases) = self.template_path(&template_name).and_then(|file_path| {
+                let text = self
+                    .source_for(&file_path)
+                    .read_to_string(&file_path)
+                    .ok()?;
+                parse_template::<TemplateAliases>(&file_path, &text).ok()
+            }) else {
+                continue;
+            };
+            for alias in template_aliases.aliases {
+                alias_map
+                    .entry(alias)
+                    .or_insert_with(|| template_name.clone());
+            }
+        }
+        let alias_map = Arc::new(alias_map);
+        *aliases = Some(Arc::clone(&alias_map));
+        Ok(alias_map)
+    }
+
+    /// Get the metadata of every template in the templates directory, sorted
+    /// by template name. The metadata is read once and kept until
+    /// `forget_template_files` is called. Templates that fail to load are
+    /// left out, as validating them reports why.
+    ///
+    /// # Errors
+    /// Will return Err if `get_template_names` returns Err.
+    pub fn get_all_metadata(&self) -> Result<Arc<MetadataList>, AutomemeError> {
+        if let Some(metadata) = &*self.metadata.read().unwrap_or_else(PoisonError::into_inner) {
+            return Ok(Arc::clone(metadata));
+        }
+        let mut metadata = self
+            .metadata
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(metadata) = &*metadata {
+            return Ok(Arc::clone(metadata));
+        }
+        let all_metadata: Arc<MetadataList> = Arc::new(
+            self.get_template_names()?
+                .into_iter()
+                .filter_map(|template_name| {
+                    let template_json = self.get_json_from_disk(&template_name).ok()?;
+                    Some((template_name, template_json.metadata))
+                })
+                .collect(),
+        );
+        *metadata = Some(Arc::clone(&all_metadata));
+        Ok(all_metadata)
+    }
+
+    /// Drop the aliases and metadata read from the template files, so they
+    /// are read again when next needed. Call this when template files are
+    /// added, removed, or edited.
+    pub fn forget_template_files(&self) {
+        *self.aliases.write().unwrap_or_else(PoisonError::into_inner) = None;
+        *self
+            .metadata
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    /// Find and read the file defining a template. Returns the source the
+    /// file is in, the path of the file, and its contents.
+    ///
+    /// # Errors
+    /// Will return Err if no template by that name exists or the template
+    /// file cannot be read.
+    pub(crate) fn read_template_file(
+        &self,
+        template_name: &str,
+    ) -> Result<(&TemplateSource, PathBuf, String), AutomemeError> {
+        let template_name = self.resolve_template_name(template_name)?;
+        let (source, file_path) = self
+            .template_paths(&template_name)
+            .into_iter()
+            .next()
+            .ok_or_else(|| AutomemeError::TemplateNotFound(template_name.clone()))?;
+        let text = source.read_to_string(&file_path)?;
+        Ok((source, file_path, text))
+    }
+
+    /// Read and deserialize a selected template file without checking its
+    /// contents. Templates that extend another are merged with it first.
+    ///
+    /// # Errors
+    /// Will return Err if:
+    /// - no template by that name exists
+    /// - the template file cannot be read
+    /// - the struct cannot be deserialized from the file
+    /// - the template extends a template that is missing or extends it back
+    pub(crate) fn read_json_from_disk(
+        &self,
+        template_name: &str,
+    ) -> Result<TemplateJSON, AutomemeError> {
+        let template_name = self.resolve_template_name(template_name)?;
+        let (source, file_path, text) = self.read_template_file(&template_name)?;
+        let template_value: Value = parse_template(&file_path, &text)?;
+        let file_value = template_value
+            .get("extends")
+            .map(|_| template_value.clone());
+        let (template_value, parent_files) = self.extend_template(
+            &template_name,
+            template_value,
+            source,
+            &mut vec![template_name.clone()],
+        )?;
+        let template_json = match serde_json::from_value(template_value) {
+            Ok(template_json) => template_json,
+            Err(e) if !parent_files.is_empty() => {
+                return Err(AutomemeError::InvalidExtends {
+                    template: template_name,
+                    message: e.to_string(),
+                })
+            }
+            // Parse the file again as a template to find where the problem is
+            Err(_) => parse_template(&file_path, &text)?,
+        };
+        let file_value = file_value.filter(|_| !parent_files.is_empty());
+        let mut files = vec![file_path];
+        files.extend(parent_files);
+        Ok(TemplateJSON {
+            root: source.root().to_path_buf(),
+            files,
+            file_value,
+            ..template_json
+        })
+    }
+
+    /// Load a selected template file from the disk.
+    ///
+    /// # Errors
+    /// Will return Err if:
+    /// - `read_json_from_disk` returns Err for the given template name
+    /// - any text field ends before it starts
+    pub fn get_json_from_disk(&self, template_name: &str) -> Result<TemplateJSON, AutomemeError> {
+        let template_name = &self.resolve_template_name(template_name)?;
+        let template_json = self.read_json_from_disk(template_name)?;
+        check_field_geometry(template_name, &template_json.text_fields)?;
+
+        Ok(template_json)
+    }
+
+    /// Load a selected template and all resources from the disk.
+    ///
+    /// # Errors
+    /// Will return Err if:
+    /// - `get_json_from_disk` returns Err for the given template name
+    /// - Image file cannot be opened or decoded
+    /// - Font file cannot be opened, read, or loaded.
+    pub fn get_template_from_disk(&self, template_name: &str) -> Result<Template, AutomemeError> {
+        // Errors name the template itself rather than the alias it was found by
+        let template_name = &self.resolve_template_name(template_name)?;
+        let template_json = self.get_json_from_disk(template_name)?;
+
+        // Open and decode the image and fonts
+        let image = self.load_image(&template_json)?;
+        let font = self.load_font(
+            &self.get_font_path(template_name, &template_json)?,
+            &template_json.font_face.clone().unwrap_or_default(),
+        )?;
+        let fallback_fonts = self
+            .get_fallback_font_paths(template_name, &template_json)?
+            .iter()
+            .map(|font_path| self.load_font(font_path, &FontFace::default()))
+            .collect::<Result<Vec<_>, AutomemeError>>()?;
+        let field_fonts = self
+            .get_field_font_paths(template_name, &template_json)?
+            .into_iter()
+            .map(|(font_name, font_path)| {
+                Ok((font_name, self.load_font(&font_path, &FontFace::default())?))
+            })
+            .collect::<Result<HashMap<_, _>, AutomemeError>>()?;
+
+        // Get metadata and text fields
+        let metadata = template_json.metadata;
+        let text_fields = template_json.text_fields;
+
+        // Return the template
+        Ok(Template {
+            metadata,
+            image,
+            font,
+            fallback_fonts,
+            field_fonts,
+            text_fields,
+        })
+    }
+
+    /// Open and decode the base image of a template.
+    ///
+    /// # Errors
+    /// Will return Err if the image file cannot be opened or decoded.
+    pub(crate) fn load_image(
+        &self,
+        template_json: &TemplateJSON,
+    ) -> Result<RgbaImage, AutomemeError> {
+        let image_path = TemplateStore::resolve_path(template_json, &template_json.image_path);
+        let image_bytes = self.read(&image_path)?;
+        let image = image::load_from_memory(&image_bytes).map_err(|e| match e {
+            image::ImageError::IoError(source) => AutomemeError::Io {
+                path: image_path.clone(),
+                source,
+            },
+            source => AutomemeError::ImageDecode {
+                path: image_path.clone(),
+                source,
+            },
+        })?;
+        Ok(image.to_rgba8())
+    }
+
+    /// Load a face of a font through the font registry, reading it from this
+    /// store's files if it has not been loaded before.
+    ///
+    /// # Errors
+    /// Will return Err if the font file cannot be opened, read, or loaded, or
+    /// has no such face.
+    pub(crate) fn load_font(
+        &self,
+        font_path: &Path,
+        face: &FontFace,
+    ) -> Result<Arc<Font>, AutomemeError> {
+        self.fonts
+            .get_font_from(font_path, face, self.source_for(font_path))
+    }
+
+    /// Get the path of the font a template uses, either from its `font_path`
+    /// or by looking up its `font` name in the fonts directory. A `font` that
+    /// is not in the fonts directory may also be a path, as it is when a
+    /// template extends one from another source.
+    ///
+    /// # Errors
+    /// Will return Err if the template names a font that cannot be found or
+    /// does not specify a font at all.
+    pub(crate) fn get_font_path(
+        &self,
+        template_name: &str,
+        template_json: &TemplateJSON,
+    ) -> Result<PathBuf, AutomemeError> {
+        if let Some(font_path) = &template_json.font_path {
+            return Ok(TemplateStore::resolve_path(template_json, font_path));
+        }
+        let font_name = template_json
+            .font
+            .as_ref()
+            .ok_or_else(|| AutomemeError::FontNotFound {
+                template: template_name.to_owned(),
+                font: None,
+                path: template_json.root.clone(),
+            })?;
+        self.find_font(&template_json.root, font_name)
+            .ok_or_else(|| AutomemeError::FontNotFound {
+                template: template_name.to_owned(),
+                font: Some(font_name.clone()),
+                path: template_json.root.clone(),
+            })
+    }
+
+    /// Get the paths of every fallback font a template uses: its own fallback
+    /// fonts first, followed by the store's default fallback fonts.
+    ///
+    /// # Errors
+    /// Will return Err if any of the template's fallback fonts is neither a
+    /// font in the fonts directory nor a file relative to the template root.
+    pub(crate) fn get_fallback_font_paths(
+        &self,
+        template_name: &str,
+        template_json: &TemplateJSON,
+    ) -> Result<Vec<PathBuf>, AutomemeError> {
+        let mut font_paths = template_json
+            .fallback_fonts
+            .iter()
+            .map(|font_name| self.resolve_font_name(template_name, template_json, font_name))
+            .collect::<Result<Vec<PathBuf>, AutomemeError>>()?;
+        font_paths.extend(self.fallback_fonts.iter().cloned());
+        Ok(font_paths)
+    }
+
+    /// Get the path of every font the text fields of a template use in place
+    /// of its main font, along with the name each field gives it. Each font
+    /// is only listed once.
+    ///
+    /// # Errors
+    /// Will return Err if any field's font is neither a font in the fonts
+    /// directory nor a file relative to the template root.
+    pub(crate) fn get_field_font_paths(
+        &self,
+        template_name: &str,
+        template_json: &TemplateJSON,
+    ) -> Result<Vec<(String, PathBuf)>, AutomemeError> {
+        let mut font_names: Vec<&String> = Vec::new();
+        for font_name in template_json
+            .text_fields
+            .iter()
+            .filter_map(|f| f.font.as_ref())
+        {
+            if !font_names.contains(&font_name) {
+                font_names.push(font_name);
+            }
+        }
+        font_names
+            .into_iter()
+            .map(|font_name| {
+                let font_path = self.resolve_font_name(template_name, template_json, font_name)?;
+                Ok((font_name.clone(), font_path))
+            })
+            .collect()
+    }
+
+    /// Find a font named in a template, either in the fonts directory or as a
+    /// file relative to the template root.
+    ///
+    /// # Errors
+    /// Will return Err if there is no such font.
+    fn resolve_font_name(
+        &self,
+        template_name: &str,
+        template_json: &TemplateJSON,
+        font_name: &str,
+    ) -> Result<PathBuf, AutomemeError> {
+        self.find_font(&template_json.root, font_name)
+            .ok_or_else(|| AutomemeError::FontNotFound {
+                template: template_name.to_owned(),
+                font: Some(font_name.to_owned()),
+                path: template_json.root.clone(),
+            })
+    }
+
+    /// Find a font either in the fonts directory of a template root or as a
+    /// file relative to the root.
+    pub(crate) fn find_font(&self, root: &Path, font_name: &str) -> Option<PathBuf> {
+        self.find_font_by_name(root, font_name)
+            .or_else(|| Some(root.join(font_name)).filter(|p| self.is_file(p)))
+    }
+
+    /// Find a font in the fonts directory of a template root by its file name
+    /// without extension.
+    fn find_font_by_name(&self, root: &Path, font_name: &str) -> Option<PathBuf> {
+        FONT_EXTENSIONS
+            .iter()
+            .map(|extension| {
+                root.join(FONT_DIRECTORY)
+                    .join(format!("{font_name}.{extension}"))
+            })
+            .find(|font_path| self.is_file(font_path))
+    }
+
+    /// Load each template file in the templates directory and check that all of
+    /// the referenced files (fonts, images) exist.
+    ///
+    /// # Errors
+    /// Will return Err if:
+    /// - `get_template_names` returns Err
+    /// - `get_json_from_disk` returns Err when loading a template by name
+    /// - any file referenced by a template does not exist
+    pub fn startup_check_all_resources(&self) -> Result<usize, AutomemeError> {
+        // Get list of template names from glob
+        let template_names = self.get_template_names()?;
+
+        // Load json for each template and check all referenced files exist
+        for name in &template_names {
+            let template = self.get_json_from_disk(name)?;
+            let mut file_paths = vec![
+                TemplateStore::resolve_path(&template, &template.image_path),
+                self.get_font_path(name, &template)?,
+            ];
+            file_paths.extend(self.get_fallback_font_paths(name, &template)?);
+            file_paths.extend(
+                self.get_field_font_paths(name, &template)?
+                    .into_iter()
+                    .map(|(_, font_path)| font_path),
+            );
+            for file_path in file_paths {
+                if !self.is_file(&file_path) {
+                    return Err(AutomemeError::Io {
+                        path: file_path,
+                        source: std::io::ErrorKind::NotFound.into(),
+                    });
+                }
+            }
+        }
+
+        Ok(template_names.len())
+    }
+
+    /// Load each template file in the templates directory and load everything into
+    /// a single `HashMap` for faster lookup.
+    ///
+    /// # Errors
+    /// Will return Err if:
+    /// - `get_template_names` returns Err
+    /// - `get_template_from_disk` returns Err when loading a template by name
+    pub fn startup_load_all_resources(&self) -> Result<HashMap<String, Template>, AutomemeError> {
+        // Get list of template names from glob
+        let template_names = self.get_template_names()?;
+
+        // Load all resources for each template
+        template_names
+            .into_iter()
+            .map(|name| {
+                let template = self.get_template_from_disk(&name)?;
+                Ok((name, template))
+            })
+            .collect()
+    }
+}
+
+/// Deserialize a template file as json, toml or yaml depending on its
+/// extension.
+///
+/// # Errors
+/// Will return Err if the file cannot be deserialized, with the line and
+/// column of the problem.
+pub(crate) fn parse_template<T: DeserializeOwned>(
+    file_path: &Path,
+    text: &str,
+) -> Result<T, AutomemeError> {
+    let parse_error = |line, column, message| AutomemeError::Parse {
+        path: file_path.to_path_buf(),
+        line,
+        column,
+        message,
+    };
+    match file_path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(text).map_err(|e| {
+            // toml reports a byte range rather than a line and column
+            let offset = e.span().map_or(0, |span| span.start);
+            let before = &text[..offset];
+            let line = before.matches('\n').count() + 1;
+            let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+            parse_error(line, column, e.message().to_owned())
+        }),
+        Some("yaml" | "yml") => serde_norway::from_str(text).map_err(|e| {
+            let (line, column) = e
+                .location()
+                .map_or((0, 0), |location| (location.line(), location.column()));
+            parse_error(line, column, e.to_string())
+        }),
+        _ => {
+            serde_json::from_str(text).map_err(|e| parse_error(e.line(), e.column(), e.to_string()))
+        }
+    }
+}
+
+/// Check that every text field has a positive width and height, so that
+/// rendering never has to deal with a field that ends before it starts.
+///
+/// # Errors
+/// Will return Err if any field's end is not past its start on both axes.
+fn check_field_geometry(
+    template_name: &str,
+    text_fields: &[TextField],
+) -> Result<(), AutomemeError> {
+    for (index, text_field) in text_fields.iter().enumerate() {
+        if text_field.end[0] <= text_field.start[0] || text_field.end[1] <= text_field.start[1] {
+            return Err(AutomemeError::InvalidGeometry {
+                template: template_name.to_owned(),
+                field: index,
+                message: format!(
+                    "end {:?} must be below and to the right of start {:?}",
+                    text_field.end, text_field.start
+                ),
+            });
+        }
+        if let (Some(corners), Some(warp_corners)) = (text_field.corners, text_field.warp_corners())
+        {
+            if !is_convex_quad(warp_corners) {
+                return Err(AutomemeError::InvalidGeometry {
+                    template: template_name.to_owned(),
+                    field: index,
+                    message: format!("corners {corners:?} {QUAD_ORDER}"),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fine_template, named_font_template, write_template, TEMPLATES};
+    use serde_json::json;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_metadata_skips_broken_templates() {
+        let root = tempdir().unwrap();
+        let write_titled = |title: &str| {
+            let mut template = fine_template(240);
+            template["title"] = title.into();
+            template["aliases"] = json!(["okay"]);
+            write_template(root.path(), "fine", &template);
+        };
+        write_titled("This is fine");
+        write(root.path().join("broken.json"), r#"{ "text_fields": [] }"#).unwrap();
+        let store = TemplateStore::new(root.path());
+        let titles = || -> Vec<_> {
+            store
+                .get_all_metadata()
+                .unwrap()
+                .iter()
+                .map(|(name, metadata)| (name.clone(), metadata.title.clone()))
+                .collect()
+        };
+        assert_eq!(
+            titles(),
+            [("fine".to_owned(), Some("This is fine".to_owned()))]
+        );
+        assert_eq!(
+            store.find_template_by_alias("okay").unwrap().as_deref(),
+            Some("fine")
+        );
+
+        // The metadata is kept until the template files are forgotten
+        write_titled("Everything is fine");
+        assert_eq!(
+            titles(),
+            [("fine".to_owned(), Some("This is fine".to_owned()))]
+        );
+        store.forget_template_files();
+        assert_eq!(
+            titles(),
+            [("fine".to_owned(), Some("Everything is fine".to_owned()))]
+        );
+    }
+
+    #[test]
+    fn test_alias_errors_name_template() {
+        let root = tempdir().unwrap();
+        let mut template = named_font_template("Missing-Font");
+        template["aliases"] = json!(["okay"]);
+        write_template(root.path(), "fine", &template);
+
+        let store = TemplateStore::new(root.path());
+        let Err(AutomemeError::FontNotFound { template, .. }) =
+            store.get_template_from_disk("okay")
+        else {
+            panic!("a template with a missing font should not load");
+        };
+        assert_eq!(template, "fine");
+    }
+
+    #[test]
+    fn test_toml_and_yaml_templates() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        write(
+            root.join("from-toml.toml"),
+            format!(
+                r#"
+                # Comments are allowed here
+                image_path = "{TEMPLATES}/this-is-fine.jpg"
+                font_path = "{TEMPLATES}/fonts/Anton-Regular.ttf"
+                title = "This is fine"
+
+                [[text_fields]]
+                text = "this is fine"
+                uppercase = true
+                start = [10, 10]
+                end = [240, 70]
+                text_size = 50
+                text_color = [255, 255, 255]
+                "#
+            ),
+        )
+        .unwrap();
+        write(
+            root.join("from-yaml.yaml"),
+            format!(
+                r"
+                image_path: {TEMPLATES}/this-is-fine.jpg
+                font_path: {TEMPLATES}/fonts/Anton-Regular.ttf
+                title: This is fine
+                text_fields:
+                  - text: this is fine
+                    uppercase: true
+                    start: [10, 10]
+                    end: [240, 70]
+                    text_size: 50
+                    text_color: [255, 255, 255]
+                "
+            ),
+        )
+        .unwrap();
+
+        let store = TemplateStore::new(root);
+        assert_eq!(
+            store.get_template_names().unwrap(),
+            ["from-toml", "from-yaml"]
+        );
+        for template_name in ["from-toml", "from-yaml"] {
+            let template = store.get_template_from_disk(template_name).unwrap();
+            assert_eq!(template.metadata.title.as_deref(), Some("This is fine"));
+            assert_eq!(template.text_fields[0].text, "this is fine");
+            assert_eq!(template.text_fields[0].end, [240, 70]);
+        }
+    }
+
+    #[test]
+    fn test_field_fonts() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let template = |fonts: [Option<&str>; 3]| {
+            let mut template = fine_template(240);
+            let field = template["text_fields"][0].take();
+            template["text_fields"] = fonts
+                .iter()
+                .map(|font| {
+                    let mut field = field.clone();
+                    if let Some(font) = font {
+                        field["font"] = (*font).into();
+                    }
+                    field
+                })
+                .collect();
+            template
+        };
+        let gabarito = format!("{TEMPLATES}/fonts/Gabarito-Bold.ttf");
+        write_template(
+            root,
+            "headline",
+            &template([None, Some(&gabarito), Some(&gabarito)]),
+        );
+        write_template(
+            root,
+            "missing",
+            &template([None, Some("Nonexistent"), None]),
+        );
+
+        // Fields share fonts they both use, and the others keep the main font
+        let store = TemplateStore::new(root);
+        let template = store.get_template_from_disk("headline").unwrap();
+        assert_eq!(template.field_fonts.len(), 1);
+        let fonts: Vec<_> = template
+            .text_fields
+            .iter()
+            .map(|text_field| template.fonts_for(text_field))
+            .collect();
+        assert!(Arc::ptr_eq(&fonts[0][0], &template.font));
+        assert!(!Arc::ptr_eq(&fonts[1][0], &template.font));
+        assert!(Arc::ptr_eq(&fonts[1][0], &fonts[2][0]));
+        assert!(store
+            .get_template_files("headline")
+            .unwrap()
+            .iter()
+            .any(|file_path| file_path.ends_with("Gabarito-Bold.ttf")));
+        crate::render_template(template).unwrap();
+
+        let error = store.get_template_from_disk("missing").unwrap_err();
+        assert!(matches!(
+            &error,
+            AutomemeError::FontNotFound { font: Some(font), .. } if font == "Nonexistent"
+        ));
+        assert!(error.to_string().contains(&format!(
+            "'Nonexistent', which could not be found in the fonts directory or the template root {}",
+            root.display()
+        )));
+        assert!(!store.validate_template("missing").is_valid());
+    }
+
+    #[test]
+    fn test_parse_errors_report_position() {
+        let toml_error = parse_template::<TemplateJSON>(
+            Path::new("broken.toml"),
+            "image_path = \"a.jpg\"\ntext_fields = [\n  { start = [1, 2 }\n]\n",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            toml_error,
+            AutomemeError::Parse {
+                line: 3,
+                column: 19,
+                ..
+            }
+        ));
+
+        let yaml_error = parse_template::<TemplateJSON>(
+            Path::new("broken.yaml"),
+            "image_path: a.jpg\ntext_fields:\n  - start: [1, 2\n",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            yaml_error,
+            AutomemeError::Parse {
+                line: 4,
+                column: 1,
+                ..
+            }
+        ));
+    }
+}
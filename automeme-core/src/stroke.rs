@@ -0,0 +1,148 @@
+//! Outlining text with a stroke, grown from the glyph coverage with a distance
+//! transform.
+
+use image::{GrayImage, Luma};
+
+/// Coverage from which a pixel counts as inside the text when measuring how
+/// far other pixels are from it.
+const INSIDE_COVERAGE: u8 = 128;
+
+/// Squared distance standing in for pixels with nothing inside the text in
+/// reach. Large enough to never be reached, small enough to do sums with.
+const FAR: f32 = 1e20;
+
+/// Grow a coverage mask into a stroke reaching `width` pixels past the edges
+/// of the shapes in it. The stroke is filled all the way to the shapes and
+/// antialiased at its outer edge. Takes time in proportion to the size of the
+/// mask, whatever the width.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+pub fn stroke_mask(mask: &GrayImage, width: f32) -> GrayImage {
+    if width <= 0.0 {
+        return mask.clone();
+    }
+    let (mask_width, mask_height) = (mask.width() as usize, mask.height() as usize);
+
+    // Squared distance from each pixel to the nearest pixel inside the text,
+    // first down each column and then along each row
+    let mut distances: Vec<f32> = mask
+        .pixels()
+        .map(|p| if p.0[0] >= INSIDE_COVERAGE { 0.0 } else { FAR })
+        .collect();
+    let mut transform = DistanceTransform::new(mask_width.max(mask_height));
+    let mut column = vec![0.0; mask_height];
+    for x in 0..mask_width {
+        for (y, value) in column.iter_mut().enumerate() {
+            *value = distances[y * mask_width + x];
+        }
+        transform.apply(&mut column);
+        for (y, value) in column.iter().enumerate() {
+            distances[y * mask_width + x] = *value;
+        }
+    }
+    for row in distances.chunks_exact_mut(mask_width.max(1)) {
+        transform.apply(row);
+    }
+
+    // Pixel centers inside the text are about half a pixel in from its edge,
+    // and the stroke's edge covers half of the pixels it passes through
+    GrayImage::from_fn(mask.width(), mask.height(), |x, y| {
+        let distance = distances[y as usize * mask_width + x as usize].sqrt();
+        let stroke = ((width + 1.0 - distance).clamp(0.0, 1.0) * 255.0).round() as u8;
+        Luma([stroke.max(mask.get_pixel(x, y).0[0])])
+    })
+}
+
+/// Exact one-dimensional squared distance transform, finding the lower
+/// envelope of the parabolas rooted at each sample (Felzenszwalb &
+/// Huttenlocher). Keeps its buffers between lines.
+struct DistanceTransform {
+    /// Samples whose parabolas make up the lower envelope
+    roots: Vec<usize>,
+    /// Where each parabola of the envelope starts being the lowest
+    boundaries: Vec<f32>,
+    /// Transformed values, before they are copied back
+    output: Vec<f32>,
+}
+
+impl DistanceTransform {
+    fn new(length: usize) -> Self {
+        DistanceTransform {
+            roots: vec![0; length],
+            boundaries: vec![0.0; length + 1],
+            output: vec![0.0; length],
+        }
+    }
+
+    /// Replace each value with the smallest of every value plus its squared
+    /// distance from there.
+    #[allow(clippy::cast_precision_loss)]
+    fn apply(&mut self, values: &mut [f32]) {
+        if values.is_empty() {
+            return;
+        }
+        let height = |q: usize| values[q] + (q * q) as f32;
+        let mut k = 0;
+        self.roots[0] = 0;
+        self.boundaries[0] = f32::NEG_INFINITY;
+        self.boundaries[1] = f32::INFINITY;
+        for q in 1..values.len() {
+            let mut start;
+            loop {
+                let root = self.roots[k];
+                start = (height(q) - height(root)) / (2 * (q - root)) as f32;
+                if start > self.boundaries[k] {
+                    break;
+                }
+                k -= 1;
+            }
+            k += 1;
+            self.roots[k] = q;
+            self.boundaries[k] = start;
+            self.boundaries[k + 1] = f32::INFINITY;
+        }
+
+        k = 0;
+        for (q, output) in self.output[..values.len()].iter_mut().enumerate() {
+            while self.boundaries[k + 1] < q as f32 {
+                k += 1;
+            }
+            let root = self.roots[k];
+            let offset = q.abs_diff(root) as f32;
+            *output = values[root] + offset * offset;
+        }
+        values.copy_from_slice(&self.output[..values.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stroke_mask;
+    use image::{GrayImage, Luma};
+
+    #[test]
+    fn test_stroke_is_filled_and_antialiased() {
+        // A single dot grows into a disc of the stroke width
+        let mut mask = GrayImage::new(41, 41);
+        mask.put_pixel(20, 20, Luma([255]));
+        let stroke = stroke_mask(&mask, 10.5);
+        for x in 0..41u32 {
+            let distance = x.abs_diff(20);
+            let coverage = stroke.get_pixel(x, 20).0[0];
+            if distance <= 10 {
+                assert_eq!(coverage, 255, "hole at {x}");
+            } else if distance >= 12 {
+                assert_eq!(coverage, 0, "stroke too wide at {x}");
+            }
+        }
+        assert_eq!(stroke.get_pixel(20 + 11, 20).0[0], 128);
+        assert_eq!(stroke.get_pixel(20 + 9, 20 + 9).0[0], 0);
+        assert_eq!(stroke.get_pixel(20 + 7, 20 + 7).0[0], 255);
+
+        // Partly covered edges keep their coverage, and nothing grows without
+        // a width
+        mask.put_pixel(0, 0, Luma([100]));
+        assert_eq!(stroke_mask(&mask, 10.5).get_pixel(0, 0).0[0], 100);
+        assert_eq!(stroke_mask(&mask, 0.0), mask);
+    }
+}
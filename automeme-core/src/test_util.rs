@@ -0,0 +1,51 @@
+//! Fixtures shared between the test modules, built from the bundled templates.
+
+use serde_json::Value;
+use std::fs::{read_to_string, write};
+use std::path::Path;
+
+/// The bundled template directory.
+pub const TEMPLATES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../templates");
+
+/// Read a bundled template, with its image and font paths pointing into the
+/// bundled template directory so it can be written to any template root.
+pub fn bundled_template(template_name: &str) -> Value {
+    let file_path = format!("{TEMPLATES}/{template_name}.json");
+    let mut template: Value = serde_json::from_str(&read_to_string(file_path).unwrap()).unwrap();
+    for key in ["image_path", "font_path"] {
+        let path = template[key].as_str().unwrap();
+        template[key] = format!("{TEMPLATES}/{path}").into();
+    }
+    template
+}
+
+/// The bundled this-is-fine template, with its only field ending at `end_x`.
+pub fn fine_template(end_x: u32) -> Value {
+    let mut template = bundled_template("this-is-fine");
+    template["text_fields"][0]["end"][0] = end_x.into();
+    template
+}
+
+/// The bundled this-is-fine template, finding its font by name instead of
+/// by path.
+pub fn named_font_template(font: &str) -> Value {
+    let mut template = fine_template(240);
+    template.as_object_mut().unwrap().remove("font_path");
+    template["font"] = font.into();
+    template
+}
+
+/// Write a template to `{root}/{template_name}.json`.
+pub fn write_template(root: &Path, template_name: &str, template: &Value) {
+    write(
+        root.join(format!("{template_name}.json")),
+        template.to_string(),
+    )
+    .unwrap();
+}
+
+/// Write the bundled this-is-fine template to `{root}/{template_name}.json`,
+/// with its only field ending at `end_x`.
+pub fn write_fine_template(root: &Path, template_name: &str, end_x: u32) {
+    write_template(root, template_name, &fine_template(end_x));
+}
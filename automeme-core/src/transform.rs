@@ -0,0 +1,311 @@
+//! Geometric transforms applied to text layers before they are blended onto
+//! the base image.
+
+use image::{Rgba, RgbaImage};
+
+/// Rotate a layer clockwise about its center by an angle in degrees. The
+/// rotated layer is resampled bilinearly and grown to fit every corner, so the
+/// position of its top-left corner relative to the original layer's is also
+/// returned.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn rotate_layer(layer: &RgbaImage, degrees: f32) -> (RgbaImage, (i32, i32)) {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (width, height) = (layer.width() as f32, layer.height() as f32);
+
+    // Size of the box around the rotated layer, ignoring rounding errors that
+    // would add a column of pixels at right angles
+    let rotated_width = (width * cos.abs() + height * sin.abs() - 1e-3).ceil();
+    let rotated_height = (width * sin.abs() + height * cos.abs() - 1e-3).ceil();
+    let offset = (
+        ((width - rotated_width) / 2.0).floor(),
+        ((height - rotated_height) / 2.0).floor(),
+    );
+    let center = (width / 2.0, height / 2.0);
+    let rotated_center = (center.0 - offset.0, center.1 - offset.1);
+
+    // Find where each rotated pixel came from by rotating it back
+    let rotated = RgbaImage::from_fn(rotated_width as u32, rotated_height as u32, |x, y| {
+        let dx = x as f32 + 0.5 - rotated_center.0;
+        let dy = y as f32 + 0.5 - rotated_center.1;
+        sample_bilinear(
+            layer,
+            center.0 + dx * cos + dy * sin,
+            center.1 - dx * sin + dy * cos,
+        )
+    });
+
+    (rotated, (offset.0 as i32, offset.1 as i32))
+}
+
+/// Warp a layer onto a quad with a perspective transform, so its corners land
+/// on the given corners in the order top-left, top-right, bottom-right,
+/// bottom-left. Each pixel averages several bilinear samples to keep the text
+/// and the edges of the quad smooth. Returns the warped layer along with the
+/// position of its top-left corner, which is the top-left of the box around
+/// the corners.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn warp_layer(layer: &RgbaImage, corners: [[f32; 2]; 4]) -> (RgbaImage, (i32, i32)) {
+    let min = |axis: usize| corners.iter().map(|c| c[axis]).fold(f32::MAX, f32::min);
+    let max = |axis: usize| corners.iter().map(|c| c[axis]).fold(f32::MIN, f32::max);
+    let (left, top) = (min(0).floor(), min(1).floor());
+    let (right, bottom) = (max(0).ceil(), max(1).ceil());
+
+    // Map points in the quad back to the unit square, and from there to the
+    // layer
+    let to_square = square_to_quad(corners).map(|row| row.map(f64::from));
+    let to_square = adjugate(to_square);
+    let center = corners.iter().fold([0.0, 0.0], |sum, c| {
+        [sum[0] + c[0] / 4.0, sum[1] + c[1] / 4.0]
+    });
+    let center_w = project(&to_square, f64::from(center[0]), f64::from(center[1]))[2];
+    let (width, height) = (f64::from(layer.width()), f64::from(layer.height()));
+
+    let warped = RgbaImage::from_fn((right - left) as u32, (bottom - top) as u32, |x, y| {
+        let mut sum = [0.0; 4];
+        for (sx, sy) in WARP_SAMPLES {
+            let [u, v, w] = project(
+                &to_square,
+                f64::from(left) + f64::from(x) + sx,
+                f64::from(top) + f64::from(y) + sy,
+            );
+            // Points past the horizon of the quad land on the wrong side
+            if w * center_w <= 0.0 {
+                continue;
+            }
+            // Samples outside the quad are left out, which smooths its edges,
+            // and samples inside stay clear of the transparent pixels around
+            // the layer
+            let (layer_x, layer_y) = (u / w * width, v / w * height);
+            if !(0.0..=width).contains(&layer_x) || !(0.0..=height).contains(&layer_y) {
+                continue;
+            }
+            let sample = sample_premultiplied(
+                layer,
+                layer_x.clamp(0.5, width - 0.5) as f32,
+                layer_y.clamp(0.5, height - 0.5) as f32,
+            );
+            for (total, value) in sum.iter_mut().zip(sample) {
+                *total += value / WARP_SAMPLES.len() as f32;
+            }
+        }
+        unpremultiply(sum)
+    });
+
+    (warped, (left as i32, top as i32))
+}
+
+/// Offsets inside each pixel that are sampled when warping a layer.
+const WARP_SAMPLES: [(f64, f64); 4] = [(0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)];
+
+/// Whether four corners, in the order top-left, top-right, bottom-right,
+/// bottom-left, form a quad that a layer can be warped onto: one with area
+/// that turns clockwise at every corner.
+pub(crate) fn is_convex_quad(corners: [[f32; 2]; 4]) -> bool {
+    (0..4).all(|index| {
+        let [a, b, c] = [0, 1, 2].map(|offset| corners[(index + offset) % 4]);
+        (b[0] - a[0]) * (c[1] - b[1]) - (b[1] - a[1]) * (c[0] - b[0]) > 0.0
+    })
+}
+
+/// Grow the quad a layer of the given size is warped onto so a layer with
+/// `margin` more pixels on each side lands in the same place, following the
+/// same perspective.
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn grow_quad(corners: [[f32; 2]; 4], size: (u32, u32), margin: u32) -> [[f32; 2]; 4] {
+    let to_quad = square_to_quad(corners).map(|row| row.map(f64::from));
+    let grow_x = f64::from(margin) / f64::from(size.0.max(1));
+    let grow_y = f64::from(margin) / f64::from(size.1.max(1));
+    [
+        (-grow_x, -grow_y),
+        (1.0 + grow_x, -grow_y),
+        (1.0 + grow_x, 1.0 + grow_y),
+        (-grow_x, 1.0 + grow_y),
+    ]
+    .map(|(u, v)| {
+        let [x, y, w] = project(&to_quad, u, v);
+        [(x / w) as f32, (y / w) as f32]
+    })
+}
+
+/// Find the perspective transform taking the corners of the unit square to
+/// the corners of a quad, as a 3x3 matrix.
+fn square_to_quad(corners: [[f32; 2]; 4]) -> [[f32; 3]; 3] {
+    let [[x0, y0], [x1, y1], [x2, y2], [x3, y3]] = corners;
+    let (sx, sy) = (x0 - x1 + x2 - x3, y0 - y1 + y2 - y3);
+    let (dx1, dx2, dy1, dy2) = (x1 - x2, x3 - x2, y1 - y2, y3 - y2);
+    let denominator = dx1 * dy2 - dx2 * dy1;
+
+    // Parallelograms need no perspective
+    let (g, h) = if denominator == 0.0 {
+        (0.0, 0.0)
+    } else {
+        (
+            (sx * dy2 - dx2 * sy) / denominator,
+            (dx1 * sy - sx * dy1) / denominator,
+        )
+    };
+    [
+        [x1 - x0 + g * x1, x3 - x0 + h * x3, x0],
+        [y1 - y0 + g * y1, y3 - y0 + h * y3, y0],
+        [g, h, 1.0],
+    ]
+}
+
+/// The adjugate of a 3x3 matrix, which is its inverse up to a scale that
+/// projecting a point divides out anyway.
+fn adjugate(m: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let cofactor =
+        |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    [
+        [
+            cofactor(1, 2, 1, 2),
+            -cofactor(0, 2, 1, 2),
+            cofactor(0, 1, 1, 2),
+        ],
+        [
+            -cofactor(1, 2, 0, 2),
+            cofactor(0, 2, 0, 2),
+            -cofactor(0, 1, 0, 2),
+        ],
+        [
+            cofactor(1, 2, 0, 1),
+            -cofactor(0, 2, 0, 1),
+            cofactor(0, 1, 0, 1),
+        ],
+    ]
+}
+
+/// Multiply a point by a transform matrix, without dividing by w.
+fn project(m: &[[f64; 3]; 3], x: f64, y: f64) -> [f64; 3] {
+    m.map(|row| row[0] * x + row[1] * y + row[2])
+}
+
+/// Sample a layer at a point between pixels, blending the four nearest pixels
+/// by how close each is. Pixels outside the layer are transparent. Colors are
+/// weighted by their alpha so transparent pixels don't darken the edges.
+pub(crate) fn sample_bilinear(layer: &RgbaImage, x: f32, y: f32) -> Rgba<u8> {
+    unpremultiply(sample_premultiplied(layer, x, y))
+}
+
+/// Convert a color with premultiplied alpha back to a pixel.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+pub(crate) fn unpremultiply([red, green, blue, alpha]: [f32; 4]) -> Rgba<u8> {
+    if alpha <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    Rgba([
+        (red / alpha).round() as u8,
+        (green / alpha).round() as u8,
+        (blue / alpha).round() as u8,
+        alpha.round().min(255.0) as u8,
+    ])
+}
+
+/// Sample a layer like `sample_bilinear`, but return the color with its
+/// alpha premultiplied so samples can be averaged.
+#[allow(clippy::cast_possible_truncation)]
+fn sample_premultiplied(layer: &RgbaImage, x: f32, y: f32) -> [f32; 4] {
+    // Pixel centers are at half coordinates
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+
+    let mut color = [0.0; 3];
+    let mut alpha = 0.0;
+    for (dx, dy, weight) in [
+        (0, 0, (1.0 - tx) * (1.0 - ty)),
+        (1, 0, tx * (1.0 - ty)),
+        (0, 1, (1.0 - tx) * ty),
+        (1, 1, tx * ty),
+    ] {
+        let (px, py) = (x0 as i64 + dx, y0 as i64 + dy);
+        let (Ok(px), Ok(py)) = (u32::try_from(px), u32::try_from(py)) else {
+            continue;
+        };
+        let Some(pixel) = layer.get_pixel_checked(px, py) else {
+            continue;
+        };
+        let pixel_alpha = f32::from(pixel.0[3]) * weight;
+        for (channel, value) in color.iter_mut().zip(pixel.0) {
+            *channel += f32::from(value) * pixel_alpha;
+        }
+        alpha += pixel_alpha;
+    }
+    [color[0], color[1], color[2], alpha]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_convex_quad, rotate_layer, warp_layer};
+    use image::{Rgba, RgbaImage};
+
+    /// A wide bar with a marker at its right end.
+    fn marked_bar() -> RgbaImage {
+        RgbaImage::from_fn(100, 20, |x, _| {
+            Rgba(if x >= 90 { [255, 0, 0, 255] } else { [255; 4] })
+        })
+    }
+
+    #[test]
+    fn test_rotate_layer() {
+        let layer = marked_bar();
+
+        // A quarter turn clockwise stands it up with the marker at the bottom
+        let (rotated, offset) = rotate_layer(&layer, 90.0);
+        assert_eq!(rotated.dimensions(), (20, 100));
+        assert_eq!(offset, (40, -40));
+        assert_eq!(rotated.get_pixel(10, 95).0, [255, 0, 0, 255]);
+        assert_eq!(rotated.get_pixel(10, 5).0, [255; 4]);
+
+        // A slight tilt grows the layer and leaves the corners empty
+        let (rotated, offset) = rotate_layer(&layer, 10.0);
+        assert!(rotated.width() > 100 && rotated.height() > 20);
+        assert!(offset.0 < 0 && offset.1 < 0);
+        assert_eq!(rotated.get_pixel(0, 0).0[3], 0);
+        let center = rotated.get_pixel(rotated.width() / 2, rotated.height() / 2);
+        assert_eq!(center.0, [255; 4]);
+    }
+
+    #[test]
+    fn test_warp_layer() {
+        let layer = marked_bar();
+
+        // Warping onto its own corners keeps the layer solid to the edges
+        let corners = [[10.0, 5.0], [110.0, 5.0], [110.0, 25.0], [10.0, 25.0]];
+        let (warped, start) = warp_layer(&layer, corners);
+        assert_eq!(start, (10, 5));
+        assert_eq!(warped.dimensions(), layer.dimensions());
+        assert!(warped.pixels().all(|pixel| pixel.0[3] == 255));
+        assert_eq!(warped.get_pixel(0, 0), layer.get_pixel(0, 0));
+        assert_eq!(warped.get_pixel(99, 19), layer.get_pixel(99, 19));
+
+        // A trapezoid shrinking to the right keeps the marker on the right
+        let corners = [[0.0, 0.0], [200.0, 40.0], [200.0, 60.0], [0.0, 100.0]];
+        let (warped, start) = warp_layer(&layer, corners);
+        assert_eq!(start, (0, 0));
+        assert_eq!(warped.dimensions(), (200, 100));
+        assert_eq!(warped.get_pixel(198, 50).0, [255, 0, 0, 255]);
+        assert_eq!(warped.get_pixel(20, 50).0, [255; 4]);
+        assert_eq!(warped.get_pixel(195, 5).0[3], 0);
+        assert_eq!(warped.get_pixel(195, 95).0[3], 0);
+
+        // Edges of the quad are blended rather than cut off
+        let edge = warped.get_pixel(52, 10).0[3];
+        assert!(edge > 0 && edge < 255);
+    }
+
+    #[test]
+    fn test_convex_quads() {
+        let square = [[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0]];
+        assert!(is_convex_quad(square));
+        let [a, b, c, d] = square;
+        assert!(!is_convex_quad([a, d, c, b]));
+        assert!(!is_convex_quad([a, b, d, c]));
+        assert!(!is_convex_quad([a, a, c, d]));
+    }
+}
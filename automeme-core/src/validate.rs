@@ -0,0 +1,464 @@
+//! Deep validation of templates, reporting every problem found instead of
+//! stopping at the first one.
+
+use crate::store::QUAD_ORDER;
+use crate::transform::is_convex_quad;
+use crate::{
+    layout_text_field, AutomemeError, BackgroundFit, FontFace, Template, TemplateJSON,
+    TemplateStore, TextField,
+};
+use fontdue::Font;
+use image::RgbaImage;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Everything found wrong with a single template. Errors will prevent the
+/// template from rendering correctly, while warnings are worth a look but
+/// will not break anything.
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    /// The template this report is for
+    pub template_name: String,
+    /// Problems that may produce surprising output
+    pub warnings: Vec<String>,
+    /// Problems that will cause rendering to fail or be wrong
+    pub errors: Vec<String>,
+}
+
+impl ValidationReport {
+    /// Whether the template passed validation with no errors.
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl TemplateStore {
+    /// Validate a single template by decoding its image and font and checking
+    /// every text field against them.
+    pub fn validate_template(&self, template_name: &str) -> ValidationReport {
+        self.validate_and_load_template(template_name).0
+    }
+
+    /// Validate a single template like `validate_template`, and also return
+    /// the template built from the decoded image and fonts if it is valid.
+    pub(crate) fn validate_and_load_template(
+        &self,
+        template_name: &str,
+    ) -> (ValidationReport, Option<Template>) {
+        // Load the template file itself, nothing else can be checked without it
+        match self.read_json_from_disk(template_name) {
+            Ok(template_json) => self.validate_and_load_json(template_name, template_json),
+            Err(e) => (unreadable_report(template_name, &e), None),
+        }
+    }
+
+    /// Validate a template that has already been read from its file, and
+    /// return the template built from it if it is valid.
+    fn validate_and_load_json(
+        &self,
+        template_name: &str,
+        template_json: TemplateJSON,
+    ) -> (ValidationReport, Option<Template>) {
+        let mut report = ValidationReport {
+            template_name: template_name.to_owned(),
+            ..Default::default()
+        };
+        if template_json.text_fields.is_empty() {
+            report
+                .warnings
+                .push("Template has no text fields".to_owned());
+        }
+
+        // Decode the referenced resources
+        let image = self
+            .load_image(&template_json)
+            .map_err(|e| report.errors.push(e.to_string()))
+            .ok();
+        if template_json.font_path.is_some() && template_json.font.is_some() {
+            report
+                .warnings
+                .push("Template sets both font_path and font, font is ignored".to_owned());
+        }
+        let font = self
+            .get_font_path(template_name, &template_json)
+            .and_then(|font_path| {
+                self.load_font(
+                    &font_path,
+                    &template_json.font_face.clone().unwrap_or_default(),
+                )
+            })
+            .map_err(|e| report.errors.push(e.to_string()))
+            .ok();
+        let fallback_fonts: Vec<Arc<Font>> = self
+            .get_fallback_font_paths(template_name, &template_json)
+            .map_err(|e| report.errors.push(e.to_string()))
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|font_path| {
+                self.load_font(font_path, &FontFace::default())
+                    .map_err(|e| report.errors.push(e.to_string()))
+                    .ok()
+            })
+            .collect();
+        let field_fonts: HashMap<String, Arc<Font>> = self
+            .get_field_font_paths(template_name, &template_json)
+            .map_err(|e| report.errors.push(e.to_string()))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(font_name, font_path)| {
+                self.load_font(&font_path, &FontFace::default())
+                    .map_err(|e| report.errors.push(e.to_string()))
+                    .map(|font| (font_name, font))
+                    .ok()
+            })
+            .collect();
+
+        // Check each field against whatever loaded successfully, with its own
+        // font in place of the main one
+        for (index, text_field) in template_json.text_fields.iter().enumerate() {
+            let font = match &text_field.font {
+                Some(font_name) => field_fonts.get(font_name),
+                None => font.as_ref(),
+            };
+            let fonts = font.map(|font| {
+                let mut fonts = vec![Arc::clone(font)];
+                fonts.extend(fallback_fonts.iter().cloned());
+                fonts
+            });
+            validate_text_field(
+                &mut report,
+                index,
+                text_field,
+                image.as_ref(),
+                fonts.as_deref(),
+            );
+        }
+
+        // Everything the template needs has been decoded if nothing failed
+        let template = match (report.is_valid(), image, font) {
+            (true, Some(image), Some(font)) => Some(Template {
+                metadata: template_json.metadata,
+                image,
+                font,
+                fallback_fonts,
+                field_fonts,
+                text_fields: template_json.text_fields,
+            }),
+            _ => None,
+        };
+        (report, template)
+    }
+
+    /// Validate every template in the store. Templates hidden by another
+    /// template with the same name are reported as warnings.
+    ///
+    /// # Errors
+    /// Will return Err if `get_template_names` returns Err. Problems with
+    /// individual templates are returned in their reports instead.
+    pub fn validate_all_templates(&self) -> Result<Vec<ValidationReport>, AutomemeError> {
+        let template_names = self.get_template_names()?;
+        let (mut reports, aliases): (Vec<ValidationReport>, Vec<Vec<String>>) = template_names
+            .iter()
+            .map(|name| match self.read_json_from_disk(name) {
+                Ok(template_json) => {
+                    let aliases = template_json.metadata.aliases.clone();
+                    (self.validate_and_load_json(name, template_json).0, aliases)
+                }
+                Err(e) => (unreadable_report(name, &e), Vec::new()),
+            })
+            .unzip();
+
+        // Aliases must not shadow a template name or be claimed twice
+        let mut claimed_aliases: HashMap<String, String> = HashMap::new();
+        for (report, aliases) in reports.iter_mut().zip(aliases) {
+            for alias in aliases {
+                if template_names.contains(&alias) {
+                    report.errors.push(format!(
+                        "Alias '{alias}' is already the name of another template"
+                    ));
+                } else if let Some(other) = claimed_aliases.get(&alias) {
+                    report.errors.push(format!(
+                        "Alias '{alias}' is already used by template '{other}'"
+                    ));
+                } else {
+                    claimed_aliases.insert(alias, report.template_name.clone());
+                }
+            }
+        }
+
+        // Templates hidden by another source with the same name are never used
+        for (template_name, file_paths) in self.get_name_collisions()? {
+            let Some(report) = reports
+                .iter_mut()
+                .find(|report| report.template_name == template_name)
+            else {
+                continue;
+            };
+            for file_path in &file_paths[1..] {
+                report.warnings.push(format!(
+                    "Template hides {}, which has the same name",
+                    file_path.display()
+                ));
+            }
+        }
+
+        Ok(reports)
+    }
+}
+
+/// The report for a template whose file could not be read or parsed.
+fn unreadable_report(template_name: &str, error: &AutomemeError) -> ValidationReport {
+    ValidationReport {
+        template_name: template_name.to_owned(),
+        errors: vec![error.to_string()],
+        ..Default::default()
+    }
+}
+
+/// Check one text field's geometry, size and default text, adding anything
+/// wrong to the report.
+fn validate_text_field(
+    report: &mut ValidationReport,
+    index: usize,
+    text_field: &TextField,
+    image: Option<&RgbaImage>,
+    fonts: Option<&[Arc<Font>]>,
+) {
+    let mut has_area = true;
+    if text_field.end[0] <= text_field.start[0] || text_field.end[1] <= text_field.start[1] {
+        has_area = false;
+        report.errors.push(format!(
+            "Field {index} has no area: end {:?} must be below and to the right of start {:?}",
+            text_field.end, text_field.start
+        ));
+    }
+
+    if let Some(image) = image {
+        if text_field.end[0] > image.width() || text_field.end[1] > image.height() {
+            report.errors.push(format!(
+                "Field {index} ends at {:?}, outside of the {}x{} image",
+                text_field.end,
+                image.width(),
+                image.height()
+            ));
+        }
+        for corner in text_field.corners.iter().flatten() {
+            if corner[0] > image.width() || corner[1] > image.height() {
+                report.errors.push(format!(
+                    "Field {index} has a corner at {corner:?}, outside of the {}x{} image",
+                    image.width(),
+                    image.height()
+                ));
+            }
+        }
+    }
+
+    if let (Some(corners), Some(warp_corners)) = (text_field.corners, text_field.warp_corners()) {
+        if !is_convex_quad(warp_corners) {
+            report
+                .errors
+                .push(format!("Field {index} corners {corners:?} {QUAD_ORDER}"));
+        }
+        if text_field.rotation != 0.0 {
+            report.warnings.push(format!(
+                "Field {index} sets both corners and rotation, rotation is ignored"
+            ));
+        }
+    }
+
+    let has_size = text_field.text_size > 0.0;
+    if !has_size {
+        report.errors.push(format!(
+            "Field {index} has a text size of {}, which must be positive",
+            text_field.text_size
+        ));
+    }
+    if let Some(min_text_size) = text_field.min_text_size {
+        if min_text_size <= 0.0 {
+            report.errors.push(format!(
+                "Field {index} has a minimum text size of {min_text_size}, which must be positive"
+            ));
+        } else if min_text_size > text_field.text_size {
+            report.warnings.push(format!(
+                "Field {index} has a minimum text size of {min_text_size}, above its text size of {}",
+                text_field.text_size
+            ));
+        }
+    }
+    if let Some(line_height) = text_field.line_height.filter(|height| *height <= 0.0) {
+        report.errors.push(format!(
+            "Field {index} has a line height of {line_height}, which must be positive"
+        ));
+    }
+    validate_effects(report, index, text_field);
+
+    // Only try to fit the default text if the field could possibly hold it
+    if let Some(fonts) = fonts {
+        if has_area && has_size && !text_field.text.is_empty() {
+            validate_default_text(report, index, text_field, fonts);
+        }
+    }
+}
+
+/// Check the border, shadow and background settings of a field, adding
+/// anything wrong to the report.
+fn validate_effects(report: &mut ValidationReport, index: usize, text_field: &TextField) {
+    if let Some(border_width) = text_field.border_width {
+        if border_width < 0.0 {
+            report.errors.push(format!(
+                "Field {index} has a border width of {border_width}, which must not be negative"
+            ));
+        } else if text_field.border_color.is_none() {
+            report.warnings.push(format!(
+                "Field {index} sets a border width but no border color, so it has no border"
+            ));
+        }
+    }
+
+    for (setting, value) in [
+        ("shadow distance", text_field.shadow_distance),
+        ("shadow blur", Some(text_field.shadow_blur)),
+        ("background padding", Some(text_field.background_padding)),
+        ("background radius", Some(text_field.background_radius)),
+    ] {
+        if let Some(value) = value.filter(|value| *value < 0.0) {
+            report.errors.push(format!(
+                "Field {index} has a {setting} of {value}, which must not be negative"
+            ));
+        }
+    }
+    for (setting, opacity) in [
+        ("shadow", text_field.shadow_opacity),
+        ("background", text_field.background_opacity),
+    ] {
+        if let Some(opacity) = opacity.filter(|opacity| !(0.0..=1.0).contains(opacity)) {
+            report.errors.push(format!(
+                "Field {index} has a {setting} opacity of {opacity}, which must be from 0 to 1"
+            ));
+        }
+    }
+    let has_shadow_settings = text_field.shadow_distance.is_some()
+        || text_field.shadow_angle.is_some()
+        || text_field.shadow_blur != 0.0
+        || text_field.shadow_opacity.is_some();
+    if has_shadow_settings && text_field.shadow_color.is_none() {
+        report.warnings.push(format!(
+            "Field {index} sets up a shadow but no shadow color, so it has no shadow"
+        ));
+    }
+    let has_background_settings = text_field.background_opacity.is_some()
+        || text_field.background_fit != BackgroundFit::default()
+        || text_field.background_padding != 0.0
+        || text_field.background_radius != 0.0;
+    if has_background_settings && text_field.background_color.is_none() {
+        report.warnings.push(format!(
+            "Field {index} sets up a background but no background color, so it has no background"
+        ));
+    }
+}
+
+/// Check that the default text of a field can be drawn and fits, adding
+/// anything wrong to the report.
+#[allow(clippy::cast_precision_loss)]
+fn validate_default_text(
+    report: &mut ValidationReport,
+    index: usize,
+    text_field: &TextField,
+    fonts: &[Arc<Font>],
+) {
+    let missing: String = text_field
+        .styled_text()
+        .text()
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .filter(|c| fonts.iter().all(|font| font.lookup_glyph_index(*c) == 0))
+        .collect();
+    if !missing.is_empty() {
+        report.warnings.push(format!(
+            "Field {index} default text has characters no font can draw: {missing}"
+        ));
+    }
+    let min_text_size = text_field.min_text_size();
+    let field_height = text_field.end[1].saturating_sub(text_field.start[1]) as f32;
+    match layout_text_field(text_field, fonts) {
+        None => report.errors.push(format!(
+            "Field {index} default text does not fit at the minimum size of {min_text_size}"
+        )),
+        Some(fitted) if fitted.truncated => report.warnings.push(format!(
+            "Field {index} default text is cut off to fit at the minimum size of {min_text_size}"
+        )),
+        Some(fitted) if fitted.layout.settings().max_height > Some(field_height) => {
+            report.warnings.push(format!(
+                "Field {index} default text overflows the field at the minimum size of {min_text_size}"
+            ));
+        }
+        Some(fitted) if fitted.hyphenated => report.warnings.push(format!(
+            "Field {index} default text has words broken with hyphens to fit at size {}",
+            fitted.text_size
+        )),
+        Some(fitted) if fitted.text_size < text_field.text_size / 2.0 => {
+            report.warnings.push(format!(
+                "Field {index} default text is shrunk from size {} to {} to fit",
+                text_field.text_size, fitted.text_size
+            ));
+        }
+        Some(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fine_template, write_template};
+    use serde_json::{json, Value};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_validate_reports_every_field_problem() {
+        let root = tempdir().unwrap();
+        let mut template = fine_template(240);
+        let field = template["text_fields"][0].clone();
+        let changed_field = |changes: Value| {
+            let mut field = field.clone();
+            for (key, value) in changes.as_object().unwrap() {
+                field[key] = value.clone();
+            }
+            field
+        };
+        template["text_fields"] = json!([
+            changed_field(json!({ "start": [100, 100], "end": [50, 150] })),
+            changed_field(json!({ "end": [9000, 70], "text_size": 0 })),
+            changed_field(json!({
+                "end": [100, 70],
+                "corners": [[10, 10], [100, 70], [100, 10], [10, 70]],
+                "rotation": 10
+            })),
+            changed_field(json!({
+                "end": [100, 70],
+                "background_fit": "text",
+                "background_opacity": 2
+            })),
+        ]);
+        write_template(root.path(), "broken", &template);
+
+        let report = TemplateStore::new(root.path()).validate_template("broken");
+        assert!(!report.is_valid());
+        assert_eq!(
+            report.errors,
+            [
+                "Field 0 has no area: end [50, 150] must be below and to the right of start [100, 100]",
+                "Field 1 ends at [9000, 70], outside of the 500x239 image",
+                "Field 1 has a text size of 0, which must be positive",
+                "Field 2 corners [[10, 10], [100, 70], [100, 10], [10, 70]] must form a convex quad listed clockwise from the top-left",
+                "Field 3 has a background opacity of 2, which must be from 0 to 1",
+            ]
+        );
+        assert_eq!(
+            report.warnings,
+            [
+                "Field 2 sets both corners and rotation, rotation is ignored",
+                "Field 3 sets up a background but no background color, so it has no background",
+            ]
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! Watching template directories and reloading templates as their files change.
+
+use crate::{AutomemeError, TemplateSet, ValidationReport};
+use notify::{recommended_watcher, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::fs::canonicalize;
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread;
+
+/// Work for the thread that reloads a watched set's templates.
+#[derive(Debug)]
+pub(crate) enum WatchMessage {
+    /// Files that were created, changed or removed
+    Changed(Vec<PathBuf>),
+    /// Files of a template that was just loaded, whose directories need watching
+    Loaded(Vec<PathBuf>),
+}
+
+/// Handle for a running template watcher. Templates are reloaded for as long
+/// as this is kept alive, and watching stops when it is dropped.
+pub struct TemplateWatcher {
+    templates: Weak<TemplateSet>,
+    _watched: Arc<Mutex<Watches>>,
+    skipped: Vec<PathBuf>,
+}
+
+impl TemplateWatcher {
+    /// The roots of the sources that are not directories on the disk, such
+    /// as template packs and the embedded templates. These are not watched.
+    pub fn skipped_sources(&self) -> &[PathBuf] {
+        &self.skipped
+    }
+}
+
+impl Drop for TemplateWatcher {
+    fn drop(&mut self) {
+        if let Some(templates) = self.templates.upgrade() {
+            templates.set_watch_sender(None);
+        }
+    }
+}
+
+/// The watcher and the directories it watches.
+struct Watches {
+    watcher: RecommendedWatcher,
+    /// Source directories, watched along with everything in them
+    roots: Vec<PathBuf>,
+    /// Other directories holding a template's files, watched on their own
+    dirs: HashSet<PathBuf>,
+}
+
+impl Watches {
+    /// Watch the directory of each file that is not watched yet. Directories
+    /// that cannot be watched, such as those of files inside a pack, are
+    /// skipped.
+    fn watch_parents(&mut self, files: &[PathBuf]) {
+        for dir in files.iter().filter_map(|file_path| file_path.parent()) {
+            if self.roots.iter().any(|root| dir.starts_with(root)) || self.dirs.contains(dir) {
+                continue;
+            }
+            if self.watcher.watch(dir, RecursiveMode::NonRecursive).is_ok() {
+                self.dirs.insert(dir.to_path_buf());
+            }
+        }
+    }
+}
+
+impl TemplateSet {
+    /// Watch every directory the templates come from for changes, along with
+    /// the directory of every file a loaded template uses, such as fonts
+    /// outside the template root. Whenever a file changes, every loaded
+    /// template using that file is revalidated and swapped in if it passes,
+    /// and the report for each reload is passed to `on_reload`. Invalid
+    /// edits leave the previous version of the template in place.
+    ///
+    /// Sources that are not directories, such as template packs, cannot
+    /// change and are skipped. They are listed by
+    /// [`TemplateWatcher::skipped_sources`].
+    ///
+    /// # Errors
+    /// Will return Err if the watcher cannot be created or a template
+    /// directory cannot be watched.
+    pub fn watch<F>(self: &Arc<Self>, on_reload: F) -> Result<TemplateWatcher, AutomemeError>
+    where
+        F: Fn(&ValidationReport) + Send + 'static,
+    {
+        // Watching a directory from inside the event handler can deadlock,
+        // so events are handled on a thread of their own
+        let (sender, receiver) = channel();
+        let event_sender = sender.clone();
+        let mut watched = Watches {
+            watcher: recommended_watcher(move |event: notify::Result<Event>| {
+                let Ok(event) = event else {
+                    return;
+                };
+                if matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) {
+                    let _ = event_sender.send(WatchMessage::Changed(event.paths));
+                }
+            })
+            .map_err(AutomemeError::Watch)?,
+            roots: Vec::new(),
+            dirs: HashSet::new(),
+        };
+        let mut skipped = Vec::new();
+        for (root, is_directory) in self.store().source_roots() {
+            if !is_directory {
+                skipped.push(root.to_path_buf());
+                continue;
+            }
+            watched
+                .watcher
+                .watch(root, RecursiveMode::Recursive)
+                .map_err(AutomemeError::Watch)?;
+            watched
+                .roots
+                .push(canonicalize(root).unwrap_or_else(|_| root.to_path_buf()));
+        }
+
+        // Templates loaded from here on are sent to the thread to be watched
+        self.set_watch_sender(Some(sender));
+        watched.watch_parents(&self.loaded_files());
+        let watched = Arc::new(Mutex::new(watched));
+
+        let templates = Arc::downgrade(self);
+        let thread_watched = Arc::downgrade(&watched);
+        thread::spawn(move || {
+            for message in receiver {
+                let Some(templates) = templates.upgrade() else {
+                    return;
+                };
+                match message {
+                    WatchMessage::Changed(paths) => {
+                        for file_path in &paths {
+                            for report in templates.reload_file(file_path) {
+                                on_reload(&report);
+                            }
+                        }
+                    }
+                    WatchMessage::Loaded(files) => {
+                        let Some(watched) = thread_watched.upgrade() else {
+                            return;
+                        };
+                        watched
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .watch_parents(&files);
+                    }
+                }
+            }
+        });
+
+        Ok(TemplateWatcher {
+            templates: Arc::downgrade(self),
+            _watched: watched,
+            skipped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fine_template, write_fine_template, write_template, TEMPLATES};
+    use crate::TemplateStore;
+    use std::fs::copy;
+    use std::time::{Duration, Instant};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_watch_reloads_edited_template() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        write_fine_template(root, "fine", 240);
+        let templates = Arc::new(TemplateSet::new(TemplateStore::new(root)));
+        templates.get_template("fine").unwrap();
+        let (sender, receiver) = channel();
+        let _watcher = templates
+            .watch(move |report| {
+                let _ = sender.send(report.template_name.clone());
+            })
+            .unwrap();
+
+        // Writing the file can take more than one event, and the file may be
+        // seen half written, so wait for the edit to land
+        write_fine_template(root, "fine", 200);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while templates.get_template("fine").unwrap().text_fields[0].end[0] != 200 {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            assert!(
+                receiver.recv_timeout(timeout).is_ok(),
+                "template was not reloaded"
+            );
+        }
+    }
+
+    #[test]
+    fn test_watch_reloads_on_font_outside_root() {
+        let temp_dir = tempdir().unwrap();
+        let font_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let font_path = font_dir.path().join("font.ttf");
+        copy(format!("{TEMPLATES}/fonts/Anton-Regular.ttf"), &font_path).unwrap();
+        let mut template = fine_template(240);
+        template["font_path"] = font_path.to_str().unwrap().into();
+        write_template(root, "fine", &template);
+        let templates = Arc::new(TemplateSet::new(TemplateStore::new(root)));
+        let (sender, receiver) = channel();
+        let _watcher = templates
+            .watch(move |report| {
+                let _ = sender.send(report.template_name.clone());
+            })
+            .unwrap();
+
+        // The font's directory is only watched once the template is loaded
+        templates.get_template("fine").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            copy(format!("{TEMPLATES}/fonts/Gabarito-Bold.ttf"), &font_path).unwrap();
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            assert!(!timeout.is_zero(), "template was not reloaded");
+            if let Ok(template_name) = receiver.recv_timeout(timeout.min(Duration::from_secs(1))) {
+                assert_eq!(template_name, "fine");
+                break;
+            }
+        }
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn test_watch_skips_embedded_templates() {
+        let templates = Arc::new(TemplateSet::new(TemplateStore::embedded()));
+        let watcher = templates.watch(|_| {}).unwrap();
+        assert_eq!(watcher.skipped_sources(), [templates.store().root()]);
+    }
+}
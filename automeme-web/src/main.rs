@@ -7,15 +7,18 @@
 #![allow(clippy::unused_async)]
 #![allow(clippy::needless_pass_by_value)]
 
+use actix_web::error::ErrorInternalServerError;
 use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
 use automeme_core::{
-    get_template_from_disk, get_template_names, render_template, startup_check_all_resources,
-    Template, TextField,
+    render_template_with_fields, AutomemeError, TemplateSet, TemplateStore, TextField,
+    ValidationReport,
 };
 use image::RgbaImage;
 use maud::{html, Markup};
+use serde::Deserialize;
 use std::env;
 use std::io::{Cursor, Result, Seek, SeekFrom};
+use std::sync::Arc;
 
 const LOREM_IPSUM: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat.";
 
@@ -59,6 +62,25 @@ fn regex_text_fields(
         .collect()
 }
 
+/// Options for user-given text, read from the query string.
+#[derive(Deserialize)]
+struct TextOptions {
+    /// Whether the text is parsed for bold, italic and colored spans
+    #[serde(default)]
+    markup: bool,
+}
+
+/// Turns on markup in every text field if it was asked for. Fields that use
+/// markup in the template keep it either way.
+fn with_markup(mut text_fields: Vec<TextField>, markup: bool) -> Vec<TextField> {
+    if markup {
+        for text_field in &mut text_fields {
+            text_field.markup = true;
+        }
+    }
+    text_fields
+}
+
 /// Streams the image data to the client and tells them it's a PNG file.
 fn serve_image_to_client(image: &RgbaImage) -> HttpResponse {
     let mut png_data = Cursor::new(Vec::new());
@@ -75,10 +97,26 @@ fn serve_image_to_client(image: &RgbaImage) -> HttpResponse {
         .body(png_data.into_inner())
 }
 
+/// Logs an error from the core library and converts it into a response with
+/// the matching status code.
+fn serve_error_to_client(error: &AutomemeError) -> HttpResponse {
+    match error {
+        AutomemeError::TemplateNotFound(_) => HttpResponse::NotFound().finish(),
+        AutomemeError::TextOverflow { .. } => HttpResponse::BadRequest().body(error.to_string()),
+        _ => {
+            eprintln!("Error: {error}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
 /// Index of all templates with a little help text.
 #[get("/")]
-async fn template_index() -> Result<Markup> {
-    let template_list = get_template_names().unwrap();
+async fn template_index(templates: web::Data<TemplateSet>) -> actix_web::Result<Markup> {
+    let template_list = templates
+        .store()
+        .get_all_metadata()
+        .map_err(ErrorInternalServerError)?;
     Ok(html! {
         html {
             head {
@@ -95,17 +133,62 @@ async fn template_index() -> Result<Markup> {
                 p {
                     "If you want to edit the text of a meme, or add text to a meme with no default text, you can use the " strong { "/f" } " or " strong { "/s" } " options. The " strong { "/f " } " option allows you to overwrite the text of a meme to your own, like adding \"mfw code doesn't compile\" to the surprised pikachu template. To do this, take the default image path like " a href="pikachu" { "/pikachu" } " and add /f/{your-text} to make " a href="pikachu/f/mfw-code-doesn't-compile" { "/pikachu/f/mfw-code-doesn't-compile" } ". The " strong { "/s" } " option replaces existing text in the template to your own with the pattern /s/{old-text}/{new-text}, allowing you to quickly turn \"Wouldn't you like to know, weather boy?\" into " a href="weatherboy/s/weather-boy/type-checker" { "\"Wouldn't you like to know, type checker?\"" } " For memes with multiple fields, use | to move to the next field. Spaces are substituted from both - and _."
                 }
-                @for template_name in template_list {
+                p {
+                    "Text can be styled too: add ?markup=true to the URL, then wrap words in * for " strong { "bold" } ", in ~ for " em { "italic" } ", or in {color:...} for color, like " a href="weatherboy/s/weather-boy/{red:*type-checker*}?markup=true" { "/weatherboy/s/weather-boy/{red:*type-checker*}?markup=true" } ". Colors can be names like red or blue, or hex codes like %23ff8800. Put a \\ in front of *, ~, {, } or \\ to keep it as it is."
+                }
+                @for (template_name, metadata) in template_list.iter() {
                     a href=(template_name) {
                         img
                             src=(template_name)
-                            title=(template_name)
+                            title=(metadata.title.as_deref().unwrap_or(template_name))
+                            alt=(metadata.description.as_deref().unwrap_or(template_name))
                             style="max-height:250px; max-width:300px; margin:20px;"
                             {}
                     }
                 }
                 p {
-                    "You can find the source for this project at " a href="https://github.com/wasabipesto/automeme" { "https://github.com/wasabipesto/automeme" } "."
+                    "You can find the source for this project at " a href="https://github.com/wasabipesto/automeme" { "https://github.com/wasabipesto/automeme" } ". Image sources are listed on the " a href="credits" { "credits" } " page."
+                }
+            }
+        }
+    })
+}
+
+/// Lists where every template image came from.
+#[get("/credits")]
+async fn template_credits(templates: web::Data<TemplateSet>) -> actix_web::Result<Markup> {
+    let template_list = templates
+        .store()
+        .get_all_metadata()
+        .map_err(ErrorInternalServerError)?;
+    Ok(html! {
+        html {
+            head {
+                title { "😂 automeme credits" }
+            }
+            body style="margin:20px;" {
+                p {
+                    a href=("/") { "Back to normal index." }
+                }
+                h1 { "Credits" }
+                ul {
+                    @for (template_name, metadata) in template_list.iter() {
+                        li {
+                            a href=(template_name) {
+                                (metadata.title.as_deref().unwrap_or(template_name))
+                            }
+                            ": "
+                            @match (&metadata.source, &metadata.source_url) {
+                                (Some(source), Some(url)) => a href=(url) { (source) },
+                                (None, Some(url)) => a href=(url) { (url) },
+                                (Some(source), None) => (source),
+                                (None, None) => "Unknown source",
+                            }
+                            @if let Some(license) = &metadata.license {
+                                " (" (license) ")"
+                            }
+                        }
+                    }
                 }
             }
         }
@@ -114,8 +197,11 @@ async fn template_index() -> Result<Markup> {
 
 /// Renders all templates with lorem ipsum text for bounds testing.
 #[get("/lorem")]
-async fn template_index_lorem() -> Result<Markup> {
-    let template_list = get_template_names().unwrap();
+async fn template_index_lorem(templates: web::Data<TemplateSet>) -> actix_web::Result<Markup> {
+    let template_list = templates
+        .store()
+        .get_template_names()
+        .map_err(ErrorInternalServerError)?;
     Ok(html! {
         html {
             head {
@@ -142,135 +228,276 @@ async fn template_index_lorem() -> Result<Markup> {
 
 /// Finds a template by name and renders it with default settings.
 #[get("/{template_name}")]
-async fn template_default(path: web::Path<String>) -> impl Responder {
+async fn template_default(
+    templates: web::Data<TemplateSet>,
+    path: web::Path<String>,
+) -> impl Responder {
     let template_name = path.into_inner();
-    match get_template_from_disk(&template_name).unwrap() {
-        Some(template) => {
+    match templates.get_template(&template_name) {
+        Ok(template) => {
             println!("Serving template {template_name} as default");
-            let image = render_template(template);
-            serve_image_to_client(&image)
+            match render_template_with_fields(&template, &template.text_fields) {
+                Ok(image) => serve_image_to_client(&image),
+                Err(e) => serve_error_to_client(&e),
+            }
         }
-        None => HttpResponse::NotFound().finish(),
+        Err(e) => serve_error_to_client(&e),
     }
 }
 
 /// Renders a template with entirely user-given text.
 #[get("/{template_name}/f/{full_text}")]
-async fn template_fulltext(path: web::Path<(String, String)>) -> impl Responder {
+async fn template_fulltext(
+    templates: web::Data<TemplateSet>,
+    path: web::Path<(String, String)>,
+    options: web::Query<TextOptions>,
+) -> impl Responder {
     let (template_name, full_text) = path.into_inner();
-    match get_template_from_disk(&template_name).unwrap() {
-        Some(template) => {
+    match templates.get_template(&template_name) {
+        Ok(template) => {
             println!("Serving template {template_name} with fulltext");
-            let text_fields = override_text_fields(
-                template.text_fields,
-                clean_text_to_vec(path_to_clean_text(full_text)),
+            let text_fields = with_markup(
+                override_text_fields(
+                    template.text_fields.clone(),
+                    clean_text_to_vec(path_to_clean_text(full_text)),
+                ),
+                options.markup,
             );
-            let image = render_template(Template {
-                text_fields,
-                ..template
-            });
-            serve_image_to_client(&image)
+            match render_template_with_fields(&template, &text_fields) {
+                Ok(image) => serve_image_to_client(&image),
+                Err(e) => serve_error_to_client(&e),
+            }
         }
-        None => HttpResponse::NotFound().finish(),
+        Err(e) => serve_error_to_client(&e),
     }
 }
 
 /// Renders a template with lorem ipsum text.
 #[get("/{template_name}/l")]
-async fn template_lorem(path: web::Path<String>) -> impl Responder {
+async fn template_lorem(
+    templates: web::Data<TemplateSet>,
+    path: web::Path<String>,
+) -> impl Responder {
     let template_name = path.into_inner();
-    match get_template_from_disk(&template_name).unwrap() {
-        Some(template) => {
+    match templates.get_template(&template_name) {
+        Ok(template) => {
             println!("Serving template {template_name} with lorem");
             let lorem_vec = vec![String::from(LOREM_IPSUM); template.text_fields.len()];
-            let text_fields = override_text_fields(template.text_fields, lorem_vec);
-            let image = render_template(Template {
-                text_fields,
-                ..template
-            });
-            serve_image_to_client(&image)
+            let text_fields = override_text_fields(template.text_fields.clone(), lorem_vec);
+            match render_template_with_fields(&template, &text_fields) {
+                Ok(image) => serve_image_to_client(&image),
+                Err(e) => serve_error_to_client(&e),
+            }
         }
-        None => HttpResponse::NotFound().finish(),
+        Err(e) => serve_error_to_client(&e),
     }
 }
 
 /// Renders a template by replacing text via a simple pattern.
 #[get("/{template_name}/s/{old_text}/{new_text}")]
-async fn template_sed(path: web::Path<(String, String, String)>) -> impl Responder {
+async fn template_sed(
+    templates: web::Data<TemplateSet>,
+    path: web::Path<(String, String, String)>,
+    options: web::Query<TextOptions>,
+) -> impl Responder {
     let (template_name, old_text, new_text) = path.into_inner();
-    match get_template_from_disk(&template_name).unwrap() {
-        Some(template) => {
+    match templates.get_template(&template_name) {
+        Ok(template) => {
             println!("Serving template {template_name} with sed");
-            let text_fields = regex_text_fields(
-                template.text_fields,
-                path_to_clean_text(old_text),
-                path_to_clean_text(new_text),
+            let text_fields = with_markup(
+                regex_text_fields(
+                    template.text_fields.clone(),
+                    path_to_clean_text(old_text),
+                    path_to_clean_text(new_text),
+                ),
+                options.markup,
             );
-            let image = render_template(Template {
-                text_fields,
-                ..template
-            });
-            serve_image_to_client(&image)
+            match render_template_with_fields(&template, &text_fields) {
+                Ok(image) => serve_image_to_client(&image),
+                Err(e) => serve_error_to_client(&e),
+            }
         }
-        None => HttpResponse::NotFound().finish(),
+        Err(e) => serve_error_to_client(&e),
     }
 }
 
+/// Register every page of the site.
+fn routes(config: &mut web::ServiceConfig) {
+    config
+        .service(template_index)
+        .service(template_index_lorem)
+        .service(template_credits)
+        .service(template_default)
+        .service(template_fulltext)
+        .service(template_lorem)
+        .service(template_sed);
+}
+
 /// Server startup tasks.
 #[actix_web::main]
 async fn main() -> Result<()> {
     // Validate resources
-    let num_templates = startup_check_all_resources().unwrap();
+    let store = match env::var("TEMPLATE_DIR") {
+        Ok(template_dir) => TemplateStore::new(template_dir),
+        #[cfg(feature = "embed")]
+        Err(_) => TemplateStore::embedded(),
+        #[cfg(not(feature = "embed"))]
+        Err(_) => TemplateStore::new("templates"),
+    }
+    .with_fallback_fonts(
+        env::var_os("TEMPLATE_FALLBACK_FONTS")
+            .map(|paths| env::split_paths(&paths).collect::<Vec<_>>())
+            .unwrap_or_default(),
+    );
+    let store = env::var_os("TEMPLATE_PACKS")
+        .map(|paths| env::split_paths(&paths).collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+        .try_fold(store, TemplateStore::with_pack)
+        .unwrap_or_else(|e| {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        });
+    let reports = match store.validate_all_templates() {
+        Ok(reports) => reports,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+    };
+    for report in &reports {
+        for warning in &report.warnings {
+            println!("Warning: {}: {warning}", report.template_name);
+        }
+        for error in &report.errors {
+            eprintln!("Error: {}: {error}", report.template_name);
+        }
+    }
+    if !reports.iter().all(ValidationReport::is_valid) {
+        std::process::exit(1);
+    }
+    let num_templates = reports.len();
+
+    // Keep decoded templates in memory, optionally bounded and preloaded
+    let mut templates = TemplateSet::new(store);
+    if let Ok(cache_size) = env::var("TEMPLATE_CACHE_SIZE") {
+        match cache_size.parse() {
+            Ok(cache_size) => templates = templates.with_capacity(cache_size),
+            Err(e) => {
+                eprintln!("Error: TEMPLATE_CACHE_SIZE '{cache_size}' is not a number: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    if env::var("TEMPLATE_PRELOAD").is_ok_and(|v| v == "1" || v == "true") {
+        match templates.preload() {
+            Ok(count) => println!("Preloaded {count} templates."),
+            Err(e) => {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    let templates = Arc::new(templates);
+
+    // Optionally reload templates as they are edited
+    let _watcher = if env::var("TEMPLATE_WATCH").is_ok_and(|v| v == "1" || v == "true") {
+        let watcher = templates.watch(|report| {
+            for warning in &report.warnings {
+                println!("Warning: {}: {warning}", report.template_name);
+            }
+            for error in &report.errors {
+                eprintln!("Error: {}: {error}", report.template_name);
+            }
+            if report.is_valid() {
+                println!("Reloaded template {}", report.template_name);
+            } else {
+                eprintln!("Keeping previous version of {}", report.template_name);
+            }
+        });
+        match watcher {
+            Ok(watcher) => {
+                for root in watcher.skipped_sources() {
+                    println!(
+                        "Warning: not watching {}, which is not a directory",
+                        root.display()
+                    );
+                }
+                Some(watcher)
+            }
+            Err(e) => {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
     println!("Server started: {num_templates} templates validated.");
+    let templates = web::Data::from(templates);
     // Start the server
-    HttpServer::new(move || {
-        App::new()
-            .service(template_index)
-            .service(template_index_lorem)
-            .service(template_default)
-            .service(template_fulltext)
-            .service(template_lorem)
-            .service(template_sed)
-    })
-    .bind(env::var("HTTP_BIND").unwrap_or(String::from("0.0.0.0:8888")))?
-    .run()
-    .await
+    HttpServer::new(move || App::new().app_data(templates.clone()).configure(routes))
+        .bind(env::var("HTTP_BIND").unwrap_or(String::from("0.0.0.0:8888")))?
+        .run()
+        .await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
     use actix_web::test;
 
+    fn test_store() -> TemplateStore {
+        TemplateStore::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../templates"))
+    }
+
+    /// The app the server runs, serving the templates in `store`.
+    fn test_app(
+        store: TemplateStore,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        App::new()
+            .app_data(web::Data::new(TemplateSet::new(store)))
+            .configure(routes)
+    }
+
     #[actix_web::test]
     async fn test_template_index() {
-        let app = test::init_service(
-            App::new()
-                .service(template_index)
-                .service(template_index_lorem)
-                .service(template_default)
-                .service(template_fulltext)
-                .service(template_lorem)
-                .service(template_sed),
-        )
-        .await;
+        let app = test::init_service(test_app(test_store())).await;
         let req = test::TestRequest::default().uri("/").to_request();
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
     }
 
+    #[actix_web::test]
+    async fn test_template_credits() {
+        let app = test::init_service(test_app(test_store())).await;
+        let req = test::TestRequest::default().uri("/credits").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_template_alias() {
+        let app = test::init_service(test_app(test_store())).await;
+        let req = test::TestRequest::default()
+            .uri("/surprised-pikachu")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
     #[actix_web::test]
     async fn test_template_pikachu_default() {
-        let app = test::init_service(
-            App::new()
-                .service(template_index)
-                .service(template_index_lorem)
-                .service(template_default)
-                .service(template_fulltext)
-                .service(template_lorem)
-                .service(template_sed),
-        )
-        .await;
+        let app = test::init_service(test_app(test_store())).await;
         let req = test::TestRequest::default().uri("/pikachu").to_request();
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
@@ -278,16 +505,7 @@ mod tests {
 
     #[actix_web::test]
     async fn test_template_pikachu_fulltext() {
-        let app = test::init_service(
-            App::new()
-                .service(template_index)
-                .service(template_index_lorem)
-                .service(template_default)
-                .service(template_fulltext)
-                .service(template_lorem)
-                .service(template_sed),
-        )
-        .await;
+        let app = test::init_service(test_app(test_store())).await;
         let req = test::TestRequest::default()
             .uri("/pikachu/f/a")
             .to_request();
@@ -295,39 +513,41 @@ mod tests {
         assert!(resp.status().is_success());
     }
 
+    #[actix_web::test]
+    async fn test_template_weatherboy_markup() {
+        let app = test::init_service(test_app(test_store())).await;
+        let req = test::TestRequest::default()
+            .uri("/weatherboy/s/weather-boy/%7Bred:*type-checker*%7D?markup=true")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
     #[actix_web::test]
     async fn test_template_pikachu_lorem() {
-        let app = test::init_service(
-            App::new()
-                .service(template_index)
-                .service(template_index_lorem)
-                .service(template_default)
-                .service(template_fulltext)
-                .service(template_lorem)
-                .service(template_sed),
-        )
-        .await;
+        let app = test::init_service(test_app(test_store())).await;
         let req = test::TestRequest::default().uri("/pikachu/l").to_request();
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
     }
 
     #[actix_web::test]
-    #[ignore]
+    async fn test_template_missing() {
+        let app = test::init_service(test_app(test_store())).await;
+        let req = test::TestRequest::default()
+            .uri("/not-a-template")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    #[ignore = "renders every template, slow"]
     async fn test_templates_all_default() {
-        let app = test::init_service(
-            App::new()
-                .service(template_index)
-                .service(template_index_lorem)
-                .service(template_default)
-                .service(template_fulltext)
-                .service(template_lorem)
-                .service(template_sed),
-        )
-        .await;
-        for template_name in get_template_names().unwrap() {
+        let app = test::init_service(test_app(test_store())).await;
+        for template_name in test_store().get_template_names().unwrap() {
             let req = test::TestRequest::default()
-                .uri(&format!("/{}", template_name))
+                .uri(&format!("/{template_name}"))
                 .to_request();
             let resp = test::call_service(&app, req).await;
             assert!(resp.status().is_success());
@@ -335,21 +555,12 @@ mod tests {
     }
 
     #[actix_web::test]
-    #[ignore]
+    #[ignore = "renders every template, slow"]
     async fn test_templates_all_fulltext() {
-        let app = test::init_service(
-            App::new()
-                .service(template_index)
-                .service(template_index_lorem)
-                .service(template_default)
-                .service(template_fulltext)
-                .service(template_lorem)
-                .service(template_sed),
-        )
-        .await;
-        for template_name in get_template_names().unwrap() {
+        let app = test::init_service(test_app(test_store())).await;
+        for template_name in test_store().get_template_names().unwrap() {
             let req = test::TestRequest::default()
-                .uri(&format!("/{}/f/a", template_name))
+                .uri(&format!("/{template_name}/f/a"))
                 .to_request();
             let resp = test::call_service(&app, req).await;
             assert!(resp.status().is_success());
@@ -357,21 +568,12 @@ mod tests {
     }
 
     #[actix_web::test]
-    #[ignore]
+    #[ignore = "renders every template, slow"]
     async fn test_templates_all_lorem() {
-        let app = test::init_service(
-            App::new()
-                .service(template_index)
-                .service(template_index_lorem)
-                .service(template_default)
-                .service(template_fulltext)
-                .service(template_lorem)
-                .service(template_sed),
-        )
-        .await;
-        for template_name in get_template_names().unwrap() {
+        let app = test::init_service(test_app(test_store())).await;
+        for template_name in test_store().get_template_names().unwrap() {
             let req = test::TestRequest::default()
-                .uri(&format!("/{}/l", template_name))
+                .uri(&format!("/{template_name}/l"))
                 .to_request();
             let resp = test::call_service(&app, req).await;
             assert!(resp.status().is_success());